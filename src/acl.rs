@@ -0,0 +1,417 @@
+use crate::base_scene::{sublayers, with_sublayers};
+use crate::util::{
+    avatar_path, is_usda_prim_start, usda_closing_bracket, usda_prim_header, usda_statement_end,
+};
+use iroh_net::key::PublicKey;
+use std::sync::Arc;
+
+pub type Acls = Arc<scc::HashMap<PublicKey, PathAcl>>;
+
+// Which prim paths a peer may author in the layers it sends us. A peer may always author
+// under its own avatar, and never under anyone else's.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PathAcl {
+    // Prim path prefixes the peer may author under. None means anywhere.
+    pub allowed_prefixes: Option<Vec<String>>,
+}
+
+#[derive(PartialEq)]
+enum Access {
+    Allowed,
+    // Not allowed, but has allowed descendants.
+    Ancestor,
+    Denied,
+}
+
+fn has_prefix(path: &str, prefix: &str) -> bool {
+    let prefix = prefix.trim_end_matches('/');
+    prefix.is_empty()
+        || path == prefix
+        || (path.starts_with(prefix) && path.as_bytes().get(prefix.len()) == Some(&b'/'))
+}
+
+impl PathAcl {
+    pub fn own_avatar_only() -> Self {
+        Self {
+            allowed_prefixes: Some(Vec::new()),
+        }
+    }
+
+    pub fn unrestricted(&self) -> bool {
+        self.allowed_prefixes.is_none()
+    }
+
+    fn access(&self, owner: PublicKey, path: &str) -> Access {
+        let own_avatar = avatar_path(owner);
+
+        if has_prefix(path, &own_avatar) {
+            return Access::Allowed;
+        }
+
+        let mut ancestor = has_prefix(&own_avatar, path);
+
+        if !has_prefix(path, "/avatars") {
+            match self.allowed_prefixes.as_ref() {
+                None => return Access::Allowed,
+                Some(prefixes) => {
+                    if prefixes.iter().any(|prefix| has_prefix(path, prefix)) {
+                        return Access::Allowed;
+                    }
+                    ancestor |= prefixes.iter().any(|prefix| has_prefix(prefix, path));
+                }
+            }
+        }
+
+        if ancestor {
+            Access::Ancestor
+        } else {
+            Access::Denied
+        }
+    }
+
+    // Remove every spec from a usda layer that `owner` isn't allowed to author. Returns the
+    // filtered layer and the paths of the removed specs.
+    pub fn filter_usda(&self, owner: PublicKey, usda: &str) -> (String, Vec<String>) {
+        let mut filter = Filter {
+            access: &|path: &str| self.access(owner, path),
+            unrestricted: self.unrestricted(),
+            source: usda.as_bytes(),
+            output: String::with_capacity(usda.len()),
+            stripped: Vec::new(),
+        };
+
+        filter.statements(0, usda.len(), "");
+
+        // A sublayer composes everything in it, including opinions on other peers' avatars,
+        // so peers never get to add one whatever they may author.
+        let mut output = filter.output;
+        if !sublayers(&output).is_empty() {
+            output = with_sublayers(&output, &[]);
+            filter.stripped.push("layer sublayers".to_string());
+        }
+
+        (output, filter.stripped)
+    }
+}
+
+// Remove the prims under `prefix` from a usda layer, keeping everything else.
+pub fn remove_prims(usda: &str, prefix: &str) -> String {
+    let mut filter = Filter {
+        access: &|path: &str| {
+            if has_prefix(path, prefix) {
+                Access::Denied
+            } else {
+                Access::Allowed
+            }
+        },
+        unrestricted: true,
+        source: usda.as_bytes(),
+        output: String::with_capacity(usda.len()),
+        stripped: Vec::new(),
+    };
+
+    filter.statements(0, usda.len(), "");
+
+    filter.output
+}
+
+struct Filter<'a> {
+    access: &'a dyn Fn(&str) -> Access,
+    // Whether layer metadata is kept.
+    unrestricted: bool,
+    source: &'a [u8],
+    output: String,
+    stripped: Vec<String>,
+}
+
+impl<'a> Filter<'a> {
+    fn text(&self, start: usize, end: usize) -> &'a str {
+        // Statement boundaries are always at ascii characters.
+        std::str::from_utf8(&self.source[start..end]).unwrap_or_default()
+    }
+
+    fn statement_end(&self, index: usize, end: usize) -> usize {
+        usda_statement_end(self.source, index, end)
+    }
+
+    fn prim_header(&self, index: usize, end: usize) -> Option<(usize, &'a str)> {
+        usda_prim_header(self.source, index, end)
+    }
+
+    // Index just past the `}` matching the `{` at `index`.
+    fn matching_brace(&self, index: usize, end: usize) -> usize {
+        usda_closing_bracket(self.source, index).map_or(end, |body_end| body_end.min(end))
+    }
+
+    fn is_prim_start(&self, index: usize) -> bool {
+        is_usda_prim_start(self.source, index)
+    }
+
+    // Keep the line break in the whitespace before a removed statement, so that the next
+    // statement stays on its own line.
+    fn keep_line_break(&mut self, line_start: usize, index: usize) {
+        let whitespace = self.text(line_start, index);
+        if let Some(line_break) = whitespace.rfind('\n') {
+            self.output.push_str(&whitespace[..=line_break]);
+        }
+    }
+
+    // Filter the statements between `index` and `end`, inside the prim at `parent_path`. The
+    // prim itself isn't allowed, as allowed prims are kept as they are.
+    fn statements(&mut self, mut index: usize, end: usize, parent_path: &str) {
+        while index < end {
+            let line_start = index;
+            while index < end && self.source[index].is_ascii_whitespace() {
+                index += 1;
+            }
+            if index == end {
+                self.output.push_str(self.text(line_start, end));
+                break;
+            }
+
+            if self.is_prim_start(index) {
+                if let Some((body_start, name)) = self.prim_header(index, end) {
+                    let body_end = self.matching_brace(body_start, end);
+                    let path = format!("{}/{}", parent_path, name);
+                    let mut next = body_end;
+
+                    match (self.access)(&path) {
+                        Access::Allowed => {
+                            self.output.push_str(self.text(line_start, body_end));
+                        }
+                        Access::Ancestor => {
+                            // Keep the prim defined, but without its type, metadata or
+                            // properties.
+                            let specifier = if self.source[index..].starts_with(b"def") {
+                                "def"
+                            } else {
+                                "over"
+                            };
+                            let indent = self.text(line_start, index);
+                            self.output.push_str(indent);
+                            self.output
+                                .push_str(&format!("{} \"{}\"\n", specifier, name));
+                            self.output.push_str(indent.trim_start_matches('\n'));
+                            self.output.push('{');
+                            self.statements(body_start + 1, body_end - 1, &path);
+                            self.output.push('}');
+                        }
+                        Access::Denied => {
+                            // The line break before the prim is kept instead of the one
+                            // after it.
+                            self.keep_line_break(line_start, index);
+                            let rest = self.text(body_end, end);
+                            if let Some(line_end) = rest
+                                .find('\n')
+                                .filter(|line_end| rest[..*line_end].trim().is_empty())
+                            {
+                                next += line_end + 1;
+                            }
+                            self.stripped.push(path);
+                        }
+                    }
+
+                    index = next;
+                    continue;
+                }
+            }
+
+            let statement_end = self.statement_end(index, end);
+            let is_comment = self.source[index] == b'#';
+
+            // Properties and metadata belong to the enclosing prim, or to the layer at the top
+            // level.
+            if is_comment {
+                self.output.push_str(self.text(line_start, statement_end));
+            } else if parent_path.is_empty() && !self.unrestricted {
+                self.keep_line_break(line_start, index);
+                self.stripped.push("layer metadata".to_string());
+            } else if parent_path.is_empty() {
+                self.output.push_str(self.text(line_start, statement_end));
+            } else {
+                self.keep_line_break(line_start, index);
+                let properties = format!("{} (properties)", parent_path);
+                if self.stripped.last() != Some(&properties) {
+                    self.stripped.push(properties);
+                }
+            }
+
+            index = statement_end;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh_net::key::SecretKey;
+
+    fn filter(usda: &str) -> (String, Vec<String>) {
+        let acl = PathAcl {
+            allowed_prefixes: Some(vec!["/World/Allowed".to_string()]),
+        };
+        acl.filter_usda(SecretKey::generate().public(), usda)
+    }
+
+    #[test]
+    fn nested_prims_outside_the_prefixes_are_removed() {
+        let (filtered, stripped) = filter(
+            r#"#usda 1.0
+
+def Xform "World" (
+    kind = "group"
+)
+{
+    float size = 1
+    def "Allowed"
+    {
+        def "Child"
+        {
+        }
+    }
+    over "Denied"
+    {
+        def "Child"
+        {
+        }
+    }
+}
+"#,
+        );
+
+        assert_eq!(
+            filtered,
+            r#"#usda 1.0
+
+def "World"
+{
+    def "Allowed"
+    {
+        def "Child"
+        {
+        }
+    }
+}
+"#
+        );
+        assert_eq!(stripped, ["/World (properties)", "/World/Denied"]);
+    }
+
+    #[test]
+    fn literals_dont_end_prims() {
+        let allowed = r#"def "Allowed" (
+        doc = """a "} { doc"""
+    )
+    {
+        string text = "}\"{ @"
+        asset file = @./{x}.usda@
+        asset quoted = @@@a@"}"@@@
+        # A comment }
+    }"#;
+        let usda = format!(
+            "#usda 1.0\n\ndef \"World\"\n{{\n    {}\n    def \"Denied\" (doc = \"{{\")\n    {{\n        string text = \"}}\"\n    }}\n}}\n",
+            allowed
+        );
+
+        let (filtered, stripped) = filter(&usda);
+
+        assert_eq!(
+            filtered,
+            format!("#usda 1.0\n\ndef \"World\"\n{{\n    {}\n}}\n", allowed)
+        );
+        assert_eq!(stripped, ["/World/Denied"]);
+    }
+
+    #[test]
+    fn variant_sets_of_ancestors_are_removed() {
+        let (filtered, stripped) = filter(
+            r#"#usda 1.0
+
+over "World" (
+    variants = {
+        string look = "red"
+    }
+    prepend variantSets = "look"
+)
+{
+    variantSet "look" = {
+        "red" {
+            def "Denied"
+            {
+            }
+        }
+    }
+}
+"#,
+        );
+
+        assert_eq!(filtered, "#usda 1.0\n\nover \"World\"\n{\n}\n");
+        assert_eq!(stripped, ["/World (properties)"]);
+    }
+
+    #[test]
+    fn layer_metadata_is_only_kept_if_unrestricted() {
+        let usda = "#usda 1.0\n(\n    defaultPrim = \"World\"\n)\n\ndef \"World\"\n{\n}\n";
+        let (filtered, stripped) = filter(usda);
+        assert_eq!(filtered, "#usda 1.0\n\ndef \"World\"\n{\n}\n");
+        assert_eq!(stripped, ["layer metadata"]);
+
+        let (filtered, stripped) =
+            PathAcl::default().filter_usda(SecretKey::generate().public(), usda);
+        assert_eq!(filtered, usda);
+        assert!(stripped.is_empty());
+    }
+
+    #[test]
+    fn sublayers_are_always_removed() {
+        let owner = SecretKey::generate().public();
+        let other = SecretKey::generate().public();
+        let usda = format!(
+            "#usda 1.0\n(\n    defaultPrim = \"World\"\n    subLayers = [\n        @./{}.usda@\n    ]\n)\n\ndef \"World\"\n{{\n}}\n",
+            crate::util::avatar_name(other)
+        );
+
+        for acl in [PathAcl::default(), PathAcl::own_avatar_only()] {
+            let (filtered, stripped) = acl.filter_usda(owner, &usda);
+            assert!(sublayers(&filtered).is_empty());
+            assert!(!filtered.contains(&crate::util::avatar_name(other)));
+            assert!(stripped
+                .iter()
+                .any(|stripped| stripped.starts_with("layer")));
+        }
+
+        let (filtered, _) = PathAcl::default().filter_usda(owner, &usda);
+        assert!(filtered.contains("defaultPrim = \"World\""));
+    }
+
+    #[test]
+    fn own_avatar_is_always_allowed() {
+        let owner = SecretKey::generate().public();
+        let other = SecretKey::generate().public();
+        let usda = format!(
+            "#usda 1.0\n\ndef \"avatars\"\n{{\n    def \"{}\"\n    {{\n    }}\n    def \"{}\"\n    {{\n    }}\n}}\n",
+            crate::util::avatar_name(owner),
+            crate::util::avatar_name(other),
+        );
+
+        let (filtered, stripped) = PathAcl::own_avatar_only().filter_usda(owner, &usda);
+
+        assert_eq!(
+            filtered,
+            format!(
+                "#usda 1.0\n\ndef \"avatars\"\n{{\n    def \"{}\"\n    {{\n    }}\n}}\n",
+                crate::util::avatar_name(owner)
+            )
+        );
+        assert_eq!(stripped, [avatar_path(other)]);
+    }
+
+    #[test]
+    fn removed_prims() {
+        let usda = "#usda 1.0\n(\n    defaultPrim = \"World\"\n)\n\ndef \"World\"\n{\n}\n\ndef \"avatars\"\n{\n    def \"avatar_a\"\n    {\n    }\n}\n";
+        assert_eq!(
+            remove_prims(usda, "/avatars"),
+            "#usda 1.0\n(\n    defaultPrim = \"World\"\n)\n\ndef \"World\"\n{\n}\n\n"
+        );
+    }
+}
@@ -0,0 +1,106 @@
+use iroh_net::{key::PublicKey, NodeAddr};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+use std::sync::Arc;
+
+#[derive(Clone, Default, Serialize, Deserialize)]
+pub struct Contact {
+    pub nickname: String,
+    pub notes: String,
+    pub last_addr: Option<NodeAddr>,
+}
+
+// Known peers, persisted to disk if a path is given.
+#[derive(Clone, Default)]
+pub struct AddressBook {
+    contacts: Arc<scc::HashMap<PublicKey, Contact>>,
+    path: Option<Arc<PathBuf>>,
+}
+
+impl AddressBook {
+    pub fn load(path: Option<PathBuf>) -> anyhow::Result<Self> {
+        let contacts = scc::HashMap::default();
+
+        if let Some(path) = path.as_ref().filter(|path| path.exists()) {
+            let entries: Vec<(PublicKey, Contact)> = postcard::from_bytes(&std::fs::read(path)?)?;
+            for (node_id, contact) in entries {
+                let _ = contacts.insert(node_id, contact);
+            }
+        }
+
+        Ok(Self {
+            contacts: Arc::new(contacts),
+            path: path.map(Arc::new),
+        })
+    }
+
+    fn save(&self) {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return,
+        };
+
+        let serialized = match postcard::to_stdvec(&self.contacts()) {
+            Ok(serialized) => serialized,
+            Err(error) => {
+                log::error!("Failed to serialize address book: {}", error);
+                return;
+            }
+        };
+
+        if let Err(error) = std::fs::write(&**path, serialized) {
+            log::error!(
+                "Failed to write address book to {}: {}",
+                path.display(),
+                error
+            );
+        }
+    }
+
+    // The nickname of a node if we have one, otherwise the short form of its id.
+    pub fn name(&self, node_id: &PublicKey) -> String {
+        self.contacts
+            .read(node_id, |_, contact| contact.nickname.clone())
+            .filter(|nickname| !nickname.is_empty())
+            .unwrap_or_else(|| node_id.fmt_short())
+    }
+
+    pub fn get(&self, node_id: &PublicKey) -> Option<Contact> {
+        self.contacts.read(node_id, |_, contact| contact.clone())
+    }
+
+    // All contacts, sorted by nickname.
+    pub fn contacts(&self) -> Vec<(PublicKey, Contact)> {
+        let mut contacts = Vec::new();
+        self.contacts.scan(|node_id, contact| {
+            contacts.push((*node_id, contact.clone()));
+        });
+        contacts.sort_by(|(_, a), (_, b)| a.nickname.cmp(&b.nickname));
+        contacts
+    }
+
+    pub fn set(&self, node_id: PublicKey, contact: Contact) {
+        self.contacts.entry(node_id).insert_entry(contact);
+        self.save();
+    }
+
+    pub fn remove(&self, node_id: &PublicKey) {
+        if self.contacts.remove(node_id).is_some() {
+            self.save();
+        }
+    }
+
+    // Only updates nodes that are already in the address book.
+    pub fn update_last_addr(&self, addr: NodeAddr) {
+        let updated = self
+            .contacts
+            .update(&addr.node_id, |_, contact| {
+                contact.last_addr = Some(addr.clone());
+            })
+            .is_some();
+
+        if updated {
+            self.save();
+        }
+    }
+}
@@ -0,0 +1,355 @@
+use crate::address_book::AddressBook;
+use crate::networking::{
+    Approval, NodeApprovalDirection, NodeApprovalRequest, NodeApprovalResponse, NodeSharingPolicy,
+};
+use crate::roles::Role;
+use clap::ValueEnum;
+use iroh_net::key::PublicKey;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::{mpsc, oneshot};
+
+// Ways of approving nodes without the UI.
+#[derive(clap::Args, Debug, Clone)]
+pub struct HeadlessApproval {
+    /// File of node ids to approve without asking, one per line, each optionally followed by
+    /// a role. Re-read for every request.
+    #[arg(long = "auto-approve")]
+    pub auto_approve: Option<PathBuf>,
+    /// Ask for approval on the terminal, answered by typing commands on stdin.
+    #[arg(long = "approval-prompt")]
+    pub prompt: bool,
+    /// Unix socket that accepts the same commands as the approval prompt.
+    #[arg(long = "control-socket")]
+    pub control_socket: Option<PathBuf>,
+}
+
+pub struct PendingApproval {
+    pub node_id: PublicKey,
+    pub room: String,
+    pub direction: NodeApprovalDirection,
+    pub role: Role,
+    pub sender: Option<oneshot::Sender<NodeApprovalResponse>>,
+    pub requested_at: std::time::Instant,
+}
+
+impl PendingApproval {
+    // Whether it still needs a response.
+    pub fn is_waiting(&self) -> bool {
+        self.sender
+            .as_ref()
+            .map_or(false, |sender| !sender.is_closed())
+    }
+
+    pub fn respond(&mut self, response: NodeApprovalResponse) {
+        if let Some(sender) = self.sender.take() {
+            let _ = sender.send(response);
+        }
+    }
+}
+
+// Requests waiting for a response, shared between the UI and the headless backends.
+#[derive(Clone, Default)]
+pub struct PendingApprovals(Arc<Mutex<Vec<PendingApproval>>>);
+
+impl PendingApprovals {
+    pub fn lock(&self) -> MutexGuard<Vec<PendingApproval>> {
+        self.0.lock().unwrap()
+    }
+
+    fn add(&self, request: NodeApprovalRequest) {
+        let mut pending = self.lock();
+
+        // A node only waits for one approval per room at a time, so a new request replaces
+        // any older one that is still in the queue, keeping its place.
+        if let Some(existing) = pending
+            .iter_mut()
+            .find(|existing| existing.node_id == request.node_id && existing.room == request.room)
+        {
+            existing.direction = request.direction;
+            existing.sender = Some(request.response_sender);
+            return;
+        }
+
+        pending.push(PendingApproval {
+            node_id: request.node_id,
+            room: request.room,
+            direction: request.direction,
+            role: Role::Editor,
+            sender: Some(request.response_sender),
+            requested_at: std::time::Instant::now(),
+        });
+    }
+}
+
+fn describe_direction(direction: &NodeApprovalDirection, address_book: &AddressBook) -> String {
+    match direction {
+        NodeApprovalDirection::Incoming => "incoming".to_string(),
+        NodeApprovalDirection::Outgoing { referrer } => {
+            format!("outgoing, referred to by {}", address_book.name(referrer))
+        }
+    }
+}
+
+// The role to approve a node with if it's in the auto-approve file.
+fn auto_approve_role(path: &Path, node_id: PublicKey) -> Option<Role> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(error) => {
+            log::error!("Reading auto-approve list {}: {}", path.display(), error);
+            return None;
+        }
+    };
+
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .find_map(|line| {
+            let mut words = line.split_whitespace();
+            if PublicKey::from_str(words.next()?).ok()? != node_id {
+                return None;
+            }
+            match words.next() {
+                Some(role) => Role::from_str(role, true).ok(),
+                None => Some(Role::Editor),
+            }
+        })
+}
+
+const HELP: &str = "Commands:
+  list                   List requests waiting for approval
+  allow <id> [role]      Approve a node and share it with other nodes
+  allow-private <id> [role]
+                         Approve a node without sharing it
+  deny <id>              Deny a node
+Ids can be shortened to any unique prefix. Roles are viewer, editor or admin.";
+
+// Run a command from the prompt or control socket, returning the output.
+pub fn run_command(line: &str, pending: &PendingApprovals, address_book: &AddressBook) -> String {
+    let mut words = line.split_whitespace();
+
+    let command = match words.next() {
+        Some(command) => command,
+        None => return String::new(),
+    };
+
+    let mut pending = pending.lock();
+    pending.retain(PendingApproval::is_waiting);
+
+    if command == "list" {
+        if pending.is_empty() {
+            return "No requests waiting for approval".to_string();
+        }
+
+        return pending
+            .iter()
+            .map(|request| {
+                format!(
+                    "{} {} in room {} ({}), waiting {}s",
+                    request.node_id,
+                    address_book.name(&request.node_id),
+                    request.room,
+                    describe_direction(&request.direction, address_book),
+                    request.requested_at.elapsed().as_secs()
+                )
+            })
+            .collect::<Vec<_>>()
+            .join("\n");
+    }
+
+    if !["allow", "allow-private", "deny"].contains(&command) {
+        return HELP.to_string();
+    }
+
+    let id = match words.next() {
+        Some(id) => id,
+        None => return format!("Usage: {} <id>", command),
+    };
+
+    let role = match words.next().map(|role| Role::from_str(role, true)) {
+        None => Role::Editor,
+        Some(Ok(role)) => role,
+        Some(Err(error)) => return error,
+    };
+
+    let mut matching = pending
+        .iter_mut()
+        .filter(|request| request.node_id.to_string().starts_with(id));
+
+    let request = match (matching.next(), matching.next()) {
+        (Some(request), None) => request,
+        (None, _) => return format!("No request from {} is waiting", id),
+        (Some(_), Some(_)) => return format!("{} matches more than one node", id),
+    };
+
+    let name = address_book.name(&request.node_id);
+
+    let (response, output) = match command {
+        "allow" => (
+            NodeApprovalResponse::Approved(Approval {
+                sharing: NodeSharingPolicy::AllExcept(Default::default()),
+                role,
+            }),
+            format!("Approved {} as {}", name, role.as_str()),
+        ),
+        "allow-private" => (
+            NodeApprovalResponse::Approved(Approval {
+                sharing: NodeSharingPolicy::NoneExcept(Default::default()),
+                role,
+            }),
+            format!("Approved {} as {} without sharing", name, role.as_str()),
+        ),
+        _ => (NodeApprovalResponse::Denied, format!("Denied {}", name)),
+    };
+
+    request.respond(response);
+
+    output
+}
+
+// Answer requests from the auto-approve list, and queue the rest for the UI and the other
+// backends.
+pub async fn handle_requests(
+    options: HeadlessApproval,
+    mut requests: mpsc::UnboundedReceiver<NodeApprovalRequest>,
+    pending: PendingApprovals,
+    address_book: AddressBook,
+) {
+    while let Some(request) = requests.recv().await {
+        let name = address_book.name(&request.node_id);
+
+        if let Some(role) = options
+            .auto_approve
+            .as_ref()
+            .and_then(|path| auto_approve_role(path, request.node_id))
+        {
+            log::info!(
+                "Approved {} as {} from the auto-approve list",
+                name,
+                role.as_str()
+            );
+            let _ = request
+                .response_sender
+                .send(NodeApprovalResponse::Approved(Approval {
+                    sharing: NodeSharingPolicy::AllExcept(Default::default()),
+                    role,
+                }));
+            continue;
+        }
+
+        if options.prompt {
+            println!(
+                "{} ({}) is waiting for approval to room {}. Reply with `allow {}`, `allow-private {}` or `deny {}`.",
+                name,
+                describe_direction(&request.direction, &address_book),
+                request.room,
+                request.node_id,
+                request.node_id,
+                request.node_id
+            );
+        }
+
+        pending.add(request);
+    }
+}
+
+// Read commands from stdin on a thread of its own, as reads block.
+pub fn spawn_prompt(pending: PendingApprovals, address_book: AddressBook) {
+    std::thread::spawn(move || {
+        println!("{}", HELP);
+        for line in std::io::stdin().lock().lines() {
+            let line = match line {
+                Ok(line) => line,
+                Err(error) => {
+                    log::error!("Reading stdin: {}", error);
+                    return;
+                }
+            };
+
+            let output = run_command(&line, &pending, &address_book);
+            if !output.is_empty() {
+                println!("{}", output);
+            }
+        }
+    });
+}
+
+#[cfg(unix)]
+pub fn spawn_control_socket(
+    path: PathBuf,
+    pending: PendingApprovals,
+    address_book: AddressBook,
+) -> anyhow::Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+
+    // A socket is left behind if we didn't exit cleanly. Anything else at the path is
+    // someone else's file.
+    match std::fs::symlink_metadata(&path) {
+        Ok(metadata) if metadata.file_type().is_socket() => std::fs::remove_file(&path)?,
+        Ok(_) => {
+            return Err(anyhow::anyhow!(
+                "Not using {} as the control socket as it's not a socket",
+                path.display()
+            ))
+        }
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => {}
+        Err(error) => return Err(error.into()),
+    }
+
+    let listener = std::os::unix::net::UnixListener::bind(&path)?;
+
+    log::info!("Listening for approval commands on {}", path.display());
+
+    std::thread::spawn(move || {
+        for stream in listener.incoming() {
+            let stream = match stream {
+                Ok(stream) => stream,
+                Err(error) => {
+                    log::error!("Accepting on the control socket: {}", error);
+                    continue;
+                }
+            };
+
+            let pending = pending.clone();
+            let address_book = address_book.clone();
+
+            std::thread::spawn(move || {
+                let mut writer = match stream.try_clone() {
+                    Ok(writer) => writer,
+                    Err(error) => {
+                        log::error!("{}", error);
+                        return;
+                    }
+                };
+
+                for line in std::io::BufReader::new(stream).lines() {
+                    let line = match line {
+                        Ok(line) => line,
+                        Err(_) => return,
+                    };
+
+                    let output = run_command(&line, &pending, &address_book);
+                    if writeln!(writer, "{}", output).is_err() {
+                        return;
+                    }
+                }
+            });
+        }
+    });
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn spawn_control_socket(
+    _path: PathBuf,
+    _pending: PendingApprovals,
+    _address_book: AddressBook,
+) -> anyhow::Result<()> {
+    Err(anyhow::anyhow!(
+        "Control sockets are only supported on unix"
+    ))
+}
@@ -0,0 +1,503 @@
+use crate::ipc::PublicLayerState;
+use crate::util::skip_usda_literal;
+use bbl_usd::cpp;
+use iroh_net::key::PublicKey;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::SystemTime;
+use tokio::io::AsyncWriteExt;
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+
+pub type AssetHash = [u8; 32];
+
+// The manifests that peers have sent us.
+pub type RemoteAssets = Arc<scc::HashMap<PublicKey, AssetManifest>>;
+
+pub const MAX_ASSET_SIZE: usize = 256 * 1024 * 1024;
+
+// Any peer can list assets for us to fetch, so how many are fetched at once, how many can
+// wait their turn and the total size of those being fetched are all limited.
+const MAX_CONCURRENT_FETCHES: usize = 4;
+const MAX_PENDING_FETCHES: usize = 256;
+const MAX_FETCHING_SIZE: usize = 1024 * 1024 * 1024;
+
+// Local asset paths are rewritten to `usd-render-asset:<hash>/<file name>` in the layers we
+// send, and back to a path in the cache in the layers we receive, so that the same layer
+// resolves on every machine. The file name is kept as usd picks file formats by extension.
+const SCHEME: &str = "usd-render-asset:";
+
+// The files referenced by a node's layers that it can send, by the asset path used in the
+// layers.
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct AssetManifest {
+    pub assets: BTreeMap<String, AssetHash>,
+}
+
+pub fn hex(hash: &AssetHash) -> String {
+    hash.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn parse_hex(hex: &str) -> Option<AssetHash> {
+    let bytes = (0..hex.len())
+        .step_by(2)
+        .map(|index| {
+            hex.get(index..index + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect::<Option<Vec<u8>>>()?;
+    AssetHash::try_from(bytes.as_slice()).ok()
+}
+
+#[derive(Clone, PartialEq)]
+pub struct AssetRef {
+    pub hash: AssetHash,
+    pub name: String,
+}
+
+impl AssetRef {
+    pub fn new(hash: AssetHash, path: &str) -> Self {
+        // Only the file name, so that a peer can't make us write outside of the cache.
+        let name = Path::new(path)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .filter(|name| !name.starts_with('.'))
+            .unwrap_or_else(|| "asset".to_string());
+
+        Self { hash, name }
+    }
+
+    pub fn parse(url: &str) -> Option<Self> {
+        let (hash, name) = url.strip_prefix(SCHEME)?.split_once('/')?;
+        Some(Self::new(parse_hex(hash)?, name))
+    }
+
+    pub fn url(&self) -> String {
+        format!("{}{}/{}", SCHEME, hex(&self.hash), self.name)
+    }
+}
+
+// Fetched assets are stored by hash, so the same file is only fetched once no matter how
+// many peers reference it or what they call it.
+#[derive(Clone)]
+pub struct AssetCache {
+    directory: Arc<PathBuf>,
+    // Local files that we've put in a manifest, so that peers can fetch them.
+    shared: Arc<scc::HashMap<AssetHash, PathBuf>>,
+    // Only rehash local files when they change.
+    hashes: Arc<scc::HashMap<PathBuf, (SystemTime, AssetHash)>>,
+    // Local files too large to share, so that they're only warned about once.
+    oversized: Arc<scc::HashSet<PathBuf>>,
+    fetching: Arc<scc::HashSet<AssetHash>>,
+    fetch_slots: Arc<Semaphore>,
+    fetching_size: Arc<Semaphore>,
+}
+
+impl AssetCache {
+    pub fn new(directory: PathBuf) -> anyhow::Result<Self> {
+        std::fs::create_dir_all(&directory)?;
+
+        Ok(Self {
+            directory: Arc::new(directory),
+            shared: Default::default(),
+            hashes: Default::default(),
+            oversized: Default::default(),
+            fetching: Default::default(),
+            fetch_slots: Arc::new(Semaphore::new(MAX_CONCURRENT_FETCHES)),
+            fetching_size: Arc::new(Semaphore::new(MAX_FETCHING_SIZE)),
+        })
+    }
+
+    fn cached_path(&self, asset: &AssetRef) -> PathBuf {
+        self.directory.join(hex(&asset.hash)).join(&asset.name)
+    }
+
+    // Where the asset is on disk, if we have it.
+    pub fn local_path(&self, asset: &AssetRef) -> Option<PathBuf> {
+        self.shared
+            .read(&asset.hash, |_, path| path.clone())
+            .or_else(|| Some(self.cached_path(asset)).filter(|path| path.exists()))
+    }
+
+    // Where an asset path that a peer's layer was resolved to is in the cache, if it's there.
+    pub fn path_in_cache(&self, asset_path: &str) -> Option<PathBuf> {
+        Path::new(asset_path)
+            .strip_prefix(&*self.directory)
+            .ok()
+            .map(Path::to_owned)
+    }
+
+    // The hash of a local file, if there is one at `path`. This reads the file, so it's only
+    // called on the blocking pool.
+    fn share(&self, path: &Path) -> Option<AssetHash> {
+        let metadata = path.metadata().ok()?;
+        let modified = metadata.modified().ok()?;
+
+        if metadata.len() > MAX_ASSET_SIZE as u64 {
+            if self.oversized.insert(path.to_owned()).is_ok() {
+                log::warn!(
+                    "Not sharing asset {} as it's larger than {} bytes",
+                    path.display(),
+                    MAX_ASSET_SIZE
+                );
+            }
+            return None;
+        }
+
+        if let Some(hash) = self
+            .hashes
+            .read(path, |_, (hashed_at, hash)| {
+                (*hashed_at == modified).then_some(*hash)
+            })
+            .flatten()
+        {
+            return Some(hash);
+        }
+
+        let hash = match std::fs::read(path) {
+            Ok(data) => Sha256::digest(data).into(),
+            Err(error) => {
+                log::warn!("Failed to share asset {}: {}", path.display(), error);
+                return None;
+            }
+        };
+        self.hashes
+            .entry(path.to_owned())
+            .insert_entry((modified, hash));
+        let _ = self.shared.insert(hash, path.to_owned());
+        Some(hash)
+    }
+
+    // The file of an asset that we shared or fetched. The node fetching it checks the hash,
+    // as a shared file could have changed since.
+    pub fn path_of(&self, hash: &AssetHash) -> Option<PathBuf> {
+        match self.shared.read(hash, |_, path| path.clone()) {
+            Some(path) => Some(path),
+            None => std::fs::read_dir(self.directory.join(hex(hash)))
+                .ok()?
+                .flatten()
+                .map(|entry| entry.path())
+                .find(|path| {
+                    path.extension()
+                        .map_or(true, |extension| extension != "partial")
+                }),
+        }
+    }
+
+    // Start writing an asset. It's written to a temporary file first so that a partly
+    // written asset is never picked up.
+    pub async fn create_partial(&self, asset: &AssetRef) -> anyhow::Result<PartialAsset> {
+        let path = self.cached_path(asset);
+        tokio::fs::create_dir_all(path.parent().unwrap_or(&self.directory)).await?;
+        let partial = path.with_extension("partial");
+
+        Ok(PartialAsset {
+            file: tokio::fs::File::create(&partial).await?,
+            hasher: Sha256::new(),
+            written: 0,
+            asset: asset.clone(),
+            partial,
+            path,
+        })
+    }
+
+    // Returns false if the asset is already being fetched, or too many are.
+    pub fn start_fetch(&self, hash: AssetHash) -> bool {
+        self.fetching.len() < MAX_PENDING_FETCHES && self.fetching.insert(hash).is_ok()
+    }
+
+    // Wait for a turn to fetch an asset.
+    pub async fn fetch_slot(&self) -> anyhow::Result<OwnedSemaphorePermit> {
+        Ok(self.fetch_slots.clone().acquire_owned().await?)
+    }
+
+    // Wait until an asset of `size` bytes can be fetched without going over the total.
+    pub async fn reserve_size(&self, size: usize) -> anyhow::Result<OwnedSemaphorePermit> {
+        if size > MAX_ASSET_SIZE {
+            return Err(anyhow::anyhow!(
+                "The asset is {} bytes, more than the limit of {}",
+                size,
+                MAX_ASSET_SIZE
+            ));
+        }
+
+        Ok(self
+            .fetching_size
+            .clone()
+            .acquire_many_owned(size as u32)
+            .await?)
+    }
+
+    pub fn finish_fetch(&self, hash: &AssetHash) {
+        self.fetching.remove(hash);
+    }
+
+    // Rewrite the local files referenced by one of our layers to session urls, adding them
+    // to `assets` by their path in the layer.
+    fn to_session_urls(&self, usda: &str, assets: &mut BTreeMap<String, AssetHash>) -> String {
+        replace_asset_paths(usda, |path| {
+            // Not a local file, so peers will have to resolve it themselves.
+            let hash = self.share(Path::new(path))?;
+            assets.insert(path.to_string(), hash);
+            Some(AssetRef::new(hash, path).url())
+        })
+    }
+
+    // Rewrite the session urls in a peer's layer to where the assets are, or will be once
+    // fetched. Returns the assets that we don't have.
+    pub fn to_local_paths(&self, usda: &str) -> (String, Vec<AssetRef>) {
+        let mut missing = Vec::new();
+
+        let usda = replace_asset_paths(usda, |url| {
+            let asset = AssetRef::parse(url)?;
+            let path = match self.local_path(&asset) {
+                Some(path) => path,
+                None => {
+                    let path = self.cached_path(&asset);
+                    missing.push(asset);
+                    path
+                }
+            };
+            // Usd wants forward slashes on every platform.
+            Some(path.to_string_lossy().replace('\\', "/"))
+        });
+
+        (usda, missing)
+    }
+}
+
+// An asset being written to the cache as it's received.
+pub struct PartialAsset {
+    asset: AssetRef,
+    file: tokio::fs::File,
+    hasher: Sha256,
+    written: usize,
+    partial: PathBuf,
+    path: PathBuf,
+}
+
+impl PartialAsset {
+    pub async fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.written += data.len();
+        if self.written > MAX_ASSET_SIZE {
+            return Err(anyhow::anyhow!(
+                "Asset {} is larger than the limit of {} bytes",
+                self.asset.url(),
+                MAX_ASSET_SIZE
+            ));
+        }
+
+        self.hasher.update(data);
+        self.file.write_all(data).await?;
+        Ok(())
+    }
+
+    pub async fn finish(mut self) -> anyhow::Result<PathBuf> {
+        self.file.flush().await?;
+
+        if self.hasher.finalize().as_slice() != self.asset.hash {
+            let _ = tokio::fs::remove_file(&self.partial).await;
+            return Err(anyhow::anyhow!(
+                "Asset {} doesn't match its hash",
+                self.asset.url()
+            ));
+        }
+
+        tokio::fs::rename(&self.partial, &self.path).await?;
+        Ok(self.path)
+    }
+
+    pub async fn discard(self) {
+        drop(self.file);
+        let _ = tokio::fs::remove_file(&self.partial).await;
+    }
+}
+
+// The ranges of the asset paths referenced in a usda layer. Strings and comments are
+// skipped so that an '@' in one isn't taken as an asset path.
+fn asset_path_ranges(usda: &str) -> Vec<Range<usize>> {
+    let source = usda.as_bytes();
+    let mut ranges = Vec::new();
+    let mut index = 0;
+
+    while index < source.len() {
+        let end = match skip_usda_literal(source, index) {
+            Some(end) => end,
+            None => {
+                index += 1;
+                continue;
+            }
+        };
+
+        if source[index] == b'@' {
+            let delimiter: &[u8] = if source[index..].starts_with(b"@@@") {
+                b"@@@"
+            } else {
+                b"@"
+            };
+            let start = index + delimiter.len();
+            // An asset path that isn't closed runs to the end of the layer.
+            if end >= start + delimiter.len() && source[..end].ends_with(delimiter) {
+                let path_end = end - delimiter.len();
+                if path_end > start {
+                    ranges.push(start..path_end);
+                }
+            }
+        }
+
+        index = end;
+    }
+
+    ranges
+}
+
+// Replace every reference to the asset at `from` with `to`.
+pub fn replace_asset_path(usda: &str, from: &str, to: &str) -> String {
+    replace_asset_paths(usda, |path| (path == from).then(|| to.to_string()))
+}
+
+pub fn asset_paths(usda: &str) -> Vec<&str> {
+    asset_path_ranges(usda)
+        .into_iter()
+        .map(|range| &usda[range])
+        .collect()
+}
+
+// Replace the asset paths for which `replace` returns a replacement.
+pub fn replace_asset_paths(usda: &str, mut replace: impl FnMut(&str) -> Option<String>) -> String {
+    let mut output = String::with_capacity(usda.len());
+    let mut copied = 0;
+
+    for range in asset_path_ranges(usda) {
+        if let Some(replacement) = replace(&usda[range.clone()]) {
+            output.push_str(&usda[copied..range.start]);
+            output.push_str(&replacement);
+            copied = range.end;
+        }
+    }
+
+    output.push_str(&usda[copied..]);
+    output
+}
+
+// Rewrite our public layers to session urls for sending, and keep a manifest of the local
+// files they reference. This is done once for each update rather than for each peer, with
+// files hashed on the blocking pool.
+pub async fn publish_session_layers(
+    cache: AssetCache,
+    mut layers: watch::Receiver<PublicLayerState>,
+    session_layers: watch::Sender<PublicLayerState>,
+    manifest: watch::Sender<AssetManifest>,
+) {
+    loop {
+        let (layer_list, layer_update_indices, update_index) = {
+            let layers = layers.borrow_and_update();
+            (
+                layers.layers.clone(),
+                layers.layer_update_indices.clone(),
+                layers.update_index,
+            )
+        };
+
+        let cache = cache.clone();
+        let rewritten = tokio::task::spawn_blocking(move || {
+            let mut assets = BTreeMap::new();
+            let layers: Vec<cpp::String> = layer_list
+                .iter()
+                .map(|layer| cpp::String::new(&cache.to_session_urls(layer.as_str(), &mut assets)))
+                .collect();
+            (layers, assets)
+        })
+        .await;
+
+        let (layer_list, assets) = match rewritten {
+            Ok(rewritten) => rewritten,
+            Err(error) => {
+                log::error!("Rewriting asset paths failed: {}", error);
+                return;
+            }
+        };
+
+        // The manifest goes first so that peers can start fetching before the layers arrive.
+        manifest.send_if_modified(|manifest| {
+            if manifest.assets == assets {
+                return false;
+            }
+            manifest.assets = assets;
+            true
+        });
+
+        // Every layer that changed since the last rewrite keeps its own update index, so
+        // none are lost when several change before we get to them.
+        if !layer_list.is_empty() {
+            session_layers.send_replace(PublicLayerState {
+                layers: layer_list,
+                layer_update_indices,
+                update_index,
+            });
+        }
+
+        if layers.changed().await.is_err() {
+            return;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn asset_paths_skip_strings_and_comments() {
+        let usda = r#"#usda 1.0
+# Mail me@example.com about @not_a_path@
+def "Prim" (
+    doc = """5" tall @not_a_path@"""
+    references = @./avatar.usda@
+)
+{
+    string single = 'it\'s @not_a_path@'
+    string escaped = "\"@not_a_path@"
+    asset[] textures = [@a.png@, @@@b@c.png@@@, @@]
+}
+"#;
+
+        assert_eq!(asset_paths(usda), ["./avatar.usda", "a.png", "b@c.png"]);
+        assert!(asset_paths("asset file = @unclosed.png").is_empty());
+    }
+
+    #[test]
+    fn replaced_asset_paths() {
+        let usda = "asset a = @a.png@\nasset b = @@@a.png@@@\nstring c = \"@a.png@\"\n";
+        assert_eq!(
+            replace_asset_path(usda, "a.png", "b.png"),
+            "asset a = @b.png@\nasset b = @@@b.png@@@\nstring c = \"@a.png@\"\n"
+        );
+    }
+
+    #[test]
+    fn asset_urls_only_keep_file_names() {
+        let hash = [0xab; 32];
+
+        let asset = AssetRef::new(hash, "textures/wood.png");
+        assert_eq!(asset.name, "wood.png");
+        assert!(AssetRef::parse(&asset.url()) == Some(asset));
+
+        let url = |name: &str| format!("{}{}/{}", SCHEME, hex(&hash), name);
+        for (name, parsed) in [
+            ("../../.bashrc", "asset"),
+            ("../../etc/passwd", "passwd"),
+            ("..", "asset"),
+            ("", "asset"),
+        ] {
+            assert_eq!(AssetRef::parse(&url(name)).unwrap().name, parsed);
+        }
+
+        assert!(AssetRef::parse("./wood.png").is_none());
+        assert!(AssetRef::parse(&format!("{}abc/wood.png", SCHEME)).is_none());
+        assert!(AssetRef::parse(&format!("{}{}", SCHEME, hex(&hash))).is_none());
+    }
+}
@@ -0,0 +1,432 @@
+use crate::acl::PathAcl;
+use crate::assets;
+use crate::presence::{self, Participants};
+use crate::util::{avatar_name, millis_since_start};
+use iroh_net::key::PublicKey;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write;
+use std::path::Path;
+use std::sync::Arc;
+
+pub type AvatarPoses = Arc<scc::HashMap<PublicKey, PoseHistory>>;
+// The poses authored on peers' avatar prims in their layers.
+pub type LayerPoses = Arc<scc::HashMap<PublicKey, AvatarPose>>;
+// Peers whose avatar asset doesn't resolve, who are shown as a placeholder instead.
+pub type UnresolvedAvatars = Arc<scc::HashSet<PublicKey>>;
+
+// How far behind the latest sample remote avatars are shown, so that there is usually a
+// later sample to interpolate towards.
+const INTERPOLATION_DELAY_MS: i64 = 100;
+// How long to keep moving an avatar along its last velocity when samples stop arriving. It
+// then moves back to the last sample over the same time.
+const MAX_EXTRAPOLATION_MS: i64 = 250;
+const MAX_SAMPLES: usize = 32;
+// How long after the last sample a pose history stops being shown, so that the avatar falls
+// back to the pose in its layer. Peers stop sending samples when they're idle or out of range.
+const POSE_EXPIRY_MS: u64 = 1000;
+// Motion is streamed as pose packets, which are subject to interest management. The avatar
+// prim in the public layer only follows along at this rate and when the avatar settles.
+const LAYER_POSE_INTERVAL_MS: u64 = 2000;
+
+// The camera transform of a peer. Mirrors the translate/orient ops on their avatar prim so
+// that it can be read without going through usd.
+#[derive(Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct AvatarPose {
+    pub position: glam::Vec3,
+    pub rotation: glam::Quat,
+}
+
+impl AvatarPose {
+    pub fn from_transform(
+        transform: dolly::transform::Transform<dolly::handedness::RightHanded>,
+    ) -> Self {
+        Self {
+            position: transform.position,
+            rotation: transform.rotation,
+        }
+    }
+
+    // Avatars face towards the camera's forward direction.
+    pub fn avatar_rotation(&self) -> glam::DQuat {
+        self.rotation.as_f64() * glam::DQuat::from_rotation_y(180_f64.to_radians())
+    }
+
+    // The camera transform of an avatar prim with the given translate and orient ops.
+    fn from_avatar_transform(position: glam::DVec3, avatar_rotation: glam::DQuat) -> Self {
+        Self {
+            position: position.as_vec3(),
+            rotation: (avatar_rotation * glam::DQuat::from_rotation_y(-180_f64.to_radians()))
+                .normalize()
+                .as_f32(),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub struct PoseSample {
+    pub pose: AvatarPose,
+    // Milliseconds since the sender started.
+    pub timestamp: u64,
+}
+
+impl PoseSample {
+    pub fn new(pose: AvatarPose) -> Self {
+        Self {
+            pose,
+            timestamp: millis_since_start(),
+        }
+    }
+}
+
+// Recent pose samples from a peer, in the order they were sent.
+#[derive(Default)]
+pub struct PoseHistory {
+    samples: VecDeque<PoseSample>,
+    // The smallest difference between our clock and the sender's clock seen when receiving
+    // a sample. This is the clock offset plus the lowest latency.
+    clock_offset: Option<i64>,
+    // Our local time when the last sample was received.
+    received_at: u64,
+}
+
+impl PoseHistory {
+    pub fn push(&mut self, sample: PoseSample) {
+        // Samples can arrive out of order as each is sent on its own stream.
+        if let Some(latest) = self.samples.back() {
+            if sample.timestamp <= latest.timestamp {
+                return;
+            }
+        }
+
+        let now = millis_since_start();
+        self.received_at = now;
+
+        let offset = now as i64 - sample.timestamp as i64;
+        self.clock_offset = Some(
+            self.clock_offset
+                .map_or(offset, |current| current.min(offset)),
+        );
+
+        self.samples.push_back(sample);
+        if self.samples.len() > MAX_SAMPLES {
+            self.samples.pop_front();
+        }
+    }
+
+    fn is_expired(&self, now: u64) -> bool {
+        now.saturating_sub(self.received_at) > POSE_EXPIRY_MS
+    }
+
+    // The pose to display at our local time `now`, if samples are still arriving.
+    pub fn pose_at(&self, now: u64) -> Option<AvatarPose> {
+        if self.is_expired(now) {
+            return None;
+        }
+
+        let latest = self.samples.back()?;
+        let time = now as i64 - self.clock_offset? - INTERPOLATION_DELAY_MS;
+
+        let next_index = self
+            .samples
+            .iter()
+            .position(|sample| sample.timestamp as i64 > time);
+
+        match next_index {
+            Some(0) => Some(self.samples[0].pose),
+            Some(index) => {
+                let previous = &self.samples[index - 1];
+                let next = &self.samples[index];
+                let factor = (time - previous.timestamp as i64) as f32
+                    / (next.timestamp - previous.timestamp) as f32;
+
+                Some(AvatarPose {
+                    position: previous.pose.position.lerp(next.pose.position, factor),
+                    rotation: previous.pose.rotation.slerp(next.pose.rotation, factor),
+                })
+            }
+            None => {
+                let previous = match self.samples.len().checked_sub(2) {
+                    Some(index) => &self.samples[index],
+                    None => return Some(latest.pose),
+                };
+
+                let interval = (latest.timestamp - previous.timestamp) as f32;
+                let ahead = time - latest.timestamp as i64;
+                let ahead = if ahead > MAX_EXTRAPOLATION_MS {
+                    (2 * MAX_EXTRAPOLATION_MS - ahead).max(0)
+                } else {
+                    ahead
+                } as f32;
+                let factor = ahead / interval;
+
+                let mut rotation_delta = latest.pose.rotation * previous.pose.rotation.inverse();
+                // Take the shortest path.
+                if rotation_delta.w < 0.0 {
+                    rotation_delta = -rotation_delta;
+                }
+                let (axis, angle) = rotation_delta.to_axis_angle();
+
+                Some(AvatarPose {
+                    position: latest.pose.position
+                        + (latest.pose.position - previous.pose.position) * factor,
+                    rotation: (glam::Quat::from_axis_angle(axis, angle * factor)
+                        * latest.pose.rotation)
+                        .normalize(),
+                })
+            }
+        }
+    }
+}
+
+// Whether usd's default resolver finds an asset referenced from a remote layer. Remote
+// layers are anonymous, so relative paths are looked for from the working directory, and
+// paths that don't start with `./` or `../` in the resolver's search path after that.
+fn resolves(asset_path: &str) -> bool {
+    let path = Path::new(asset_path);
+    if path.exists() {
+        return true;
+    }
+
+    if path.is_absolute() || asset_path.starts_with("./") || asset_path.starts_with("../") {
+        return false;
+    }
+
+    std::env::var_os("PXR_AR_DEFAULT_SEARCH_PATH").map_or(false, |search_path| {
+        std::env::split_paths(&search_path).any(|directory| directory.join(path).exists())
+    })
+}
+
+// The asset paths under a node's avatar in one of its layers that don't resolve locally, or
+// None if the layer doesn't reference any assets for the avatar.
+pub fn missing_avatar_assets(owner: PublicKey, usda: &str) -> Option<Vec<String>> {
+    let (avatar, _) = PathAcl::own_avatar_only().filter_usda(owner, usda);
+    let asset_paths = assets::asset_paths(&avatar);
+
+    if asset_paths.is_empty() {
+        return None;
+    }
+
+    Some(
+        asset_paths
+            .into_iter()
+            .filter(|path| !resolves(path))
+            .map(|path| path.to_string())
+            .collect(),
+    )
+}
+
+// The values of the first `property = (...)` in a usda layer with `N` numbers.
+fn tuple_property<const N: usize>(usda: &str, property: &str) -> Option<[f64; N]> {
+    usda.match_indices(property).find_map(|(start, _)| {
+        let rest = usda[start + property.len()..]
+            .trim_start()
+            .strip_prefix('=')?
+            .trim_start()
+            .strip_prefix('(')?;
+        let values: Vec<f64> = rest[..rest.find(')')?]
+            .split(',')
+            .map(|value| value.trim().parse().ok())
+            .collect::<Option<_>>()?;
+        values.try_into().ok()
+    })
+}
+
+// The pose authored on a node's avatar prim in one of its layers, if the layer sets it.
+pub fn layer_pose(owner: PublicKey, usda: &str) -> Option<AvatarPose> {
+    let (avatar, _) = PathAcl::own_avatar_only().filter_usda(owner, usda);
+    let [x, y, z] = tuple_property(&avatar, "xformOp:translate")?;
+    let [w, i, j, k] = tuple_property(&avatar, "xformOp:orient")?;
+
+    Some(AvatarPose::from_avatar_transform(
+        glam::DVec3::new(x, y, z),
+        glam::DQuat::from_xyzw(i, j, k, w),
+    ))
+}
+
+// The pose that a peer's avatar prim composes to on our stage: the smoothed one in the
+// local-only layer while samples are arriving, and the one in their layer otherwise.
+pub fn composed_pose(
+    avatar_poses: &AvatarPoses,
+    layer_poses: &LayerPoses,
+    node_id: &PublicKey,
+    now: u64,
+) -> Option<AvatarPose> {
+    avatar_poses
+        .read(node_id, |_, history| history.pose_at(now))
+        .flatten()
+        .or_else(|| layer_poses.read(node_id, |_, pose| *pose))
+}
+
+// An `over` of every remote avatar with its smoothed pose, for the local-only layer. Avatars
+// without recent samples are left to the pose in their layer. Avatars that don't resolve get
+// a placeholder in the peer's colour.
+pub fn remote_avatars_usda(
+    avatar_poses: &AvatarPoses,
+    unresolved_avatars: &UnresolvedAvatars,
+    participants: &Participants,
+) -> String {
+    let now = millis_since_start();
+
+    let mut poses = HashMap::new();
+    avatar_poses.scan(|node_id, history| {
+        if let Some(pose) = history.pose_at(now) {
+            poses.insert(*node_id, pose);
+        }
+    });
+
+    let mut node_ids: Vec<PublicKey> = poses.keys().copied().collect();
+    unresolved_avatars.scan(|node_id| {
+        if !poses.contains_key(node_id) {
+            node_ids.push(*node_id);
+        }
+    });
+
+    let mut usda = String::from("#usda 1.0\n\nover \"avatars\"\n{\n");
+
+    for node_id in node_ids {
+        let _ = write!(usda, "    over \"{}\"\n    {{\n", avatar_name(node_id));
+
+        if let Some(pose) = poses.get(&node_id) {
+            let position = pose.position.as_dvec3();
+            let rotation = pose.avatar_rotation();
+
+            let _ = write!(
+                usda,
+                r#"        double3 xformOp:translate = ({}, {}, {})
+        quatd xformOp:orient = ({}, {}, {}, {})
+"#,
+                position.x, position.y, position.z, rotation.w, rotation.x, rotation.y, rotation.z
+            );
+        }
+
+        if unresolved_avatars.contains(&node_id) {
+            let [r, g, b] = participants
+                .read(&node_id, |_, presence| presence.colour)
+                .unwrap_or_else(|| presence::default_colour(node_id));
+
+            // A head with a nose pointing the way the peer is looking.
+            let _ = write!(
+                usda,
+                r#"        def Sphere "placeholder"
+        {{
+            double radius = 0.2
+            color3f[] primvars:displayColor = [({r}, {g}, {b})]
+
+            def Cone "nose"
+            {{
+                uniform token axis = "Z"
+                double height = 0.15
+                double radius = 0.08
+                double3 xformOp:translate = (0, 0, 0.2)
+                uniform token[] xformOpOrder = ["xformOp:translate"]
+                color3f[] primvars:displayColor = [({r}, {g}, {b})]
+            }}
+        }}
+"#
+            );
+        }
+
+        usda.push_str("    }\n");
+    }
+
+    usda.push_str("}\n");
+    usda
+}
+
+// When to send our own avatar's pose to peers.
+#[derive(clap::Args, Debug, Clone)]
+pub struct UpdatePolicy {
+    /// Maximum avatar updates per second.
+    #[arg(long = "avatar-max-rate", default_value_t = 30.0)]
+    pub max_rate: f32,
+    /// Number of connected peers above which the update rate is reduced proportionally.
+    #[arg(long = "avatar-full-rate-peers", default_value_t = 4)]
+    pub full_rate_peers: usize,
+    /// Minimum distance moved before an update is sent.
+    #[arg(long = "avatar-min-distance", default_value_t = 0.01)]
+    pub min_distance: f32,
+    /// Minimum rotation in degrees before an update is sent.
+    #[arg(long = "avatar-min-angle", default_value_t = 0.5)]
+    pub min_angle_degrees: f32,
+    /// Milliseconds without meaningful movement before sending a final exact pose and going
+    /// idle.
+    #[arg(long = "avatar-idle-ms", default_value_t = 250)]
+    pub idle_ms: u64,
+}
+
+pub struct AvatarUpdate {
+    pub send_pose: bool,
+    // The pose to author into the public layer, if any.
+    pub author_layer: Option<AvatarPose>,
+}
+
+pub struct AvatarUpdater {
+    policy: UpdatePolicy,
+    // The last pose that was sent and when.
+    sent: Option<(AvatarPose, u64)>,
+    // The last pose authored into the public layer and when.
+    authored: Option<(AvatarPose, u64)>,
+}
+
+impl AvatarUpdater {
+    pub fn new(policy: UpdatePolicy) -> Self {
+        Self {
+            policy,
+            sent: None,
+            authored: None,
+        }
+    }
+
+    fn min_interval_ms(&self, peer_count: usize) -> f32 {
+        let scale = (peer_count as f32 / self.policy.full_rate_peers.max(1) as f32).max(1.0);
+        1000.0 / self.policy.max_rate * scale
+    }
+
+    // What to do with our current pose. Records it as sent/authored accordingly.
+    pub fn update(&mut self, pose: AvatarPose, now: u64, peer_count: usize) -> AvatarUpdate {
+        let send_pose = match self.sent {
+            None => true,
+            Some((sent_pose, sent_at)) => {
+                let elapsed = now.saturating_sub(sent_at);
+
+                let moved = pose.position.distance(sent_pose.position) >= self.policy.min_distance
+                    || sent_pose.rotation.angle_between(pose.rotation).to_degrees()
+                        >= self.policy.min_angle_degrees;
+
+                // Small movements are suppressed, but once they've added up for a while send
+                // the exact pose so that peers don't show us slightly off. Nothing is sent
+                // while fully idle.
+                let settle = pose != sent_pose && elapsed >= self.policy.idle_ms;
+
+                (elapsed as f32) >= self.min_interval_ms(peer_count) && (moved || settle)
+            }
+        };
+
+        if send_pose {
+            self.sent = Some((pose, now));
+        }
+
+        let author_layer = match (self.sent, self.authored) {
+            (Some((sent_pose, sent_at)), Some((authored_pose, authored_at))) => {
+                sent_pose != authored_pose
+                    && (now.saturating_sub(sent_at) >= self.policy.idle_ms
+                        || now.saturating_sub(authored_at) >= LAYER_POSE_INTERVAL_MS)
+            }
+            (Some(_), None) => true,
+            (None, _) => false,
+        };
+
+        if author_layer {
+            self.authored = self.sent.map(|(sent_pose, _)| (sent_pose, now));
+        }
+
+        AvatarUpdate {
+            send_pose,
+            author_layer: self
+                .authored
+                .filter(|_| author_layer)
+                .map(|(authored_pose, _)| authored_pose),
+        }
+    }
+}
@@ -0,0 +1,376 @@
+use crate::util::{skip_usda_literal, usda_closing_bracket};
+use bbl_usd::sdf;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::path::{Component, Path, PathBuf};
+
+// What to do when a peer's base scene differs from ours.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, clap::ValueEnum)]
+pub enum MismatchPolicy {
+    Warn,
+    Refuse,
+}
+
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct LayerHash {
+    // Relative to the directory of the base layer, so that peers can keep the scene anywhere.
+    pub name: String,
+    pub hash: [u8; 32],
+}
+
+// The layers of the base layer stack in strength order, depth first.
+#[derive(Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct BaseScene {
+    pub layers: Vec<LayerHash>,
+}
+
+impl BaseScene {
+    pub fn hash(base: &str) -> anyhow::Result<Self> {
+        let directory = Path::new(base).parent().unwrap_or(Path::new(""));
+        let mut scene = Self::default();
+        scene.add_layer(base, directory, 0)?;
+        Ok(scene)
+    }
+
+    fn add_layer(&mut self, path: &str, directory: &Path, depth: usize) -> anyhow::Result<()> {
+        // Sublayer cycles are an error in usd anyway.
+        if depth > 32 {
+            return Err(anyhow::anyhow!(
+                "Sublayers of {} are nested too deeply",
+                path
+            ));
+        }
+
+        let layer = sdf::Layer::find_or_open(path);
+        // Hash the layer as usda so that the same content matches whether it's stored as
+        // usda or usdc.
+        let usda = layer
+            .export_to_string()
+            .map_err(|_| anyhow::anyhow!("Failed to open base layer {}", path))?;
+
+        let name = Path::new(path)
+            .strip_prefix(directory)
+            .unwrap_or(Path::new(path))
+            .display()
+            .to_string();
+
+        self.layers.push(LayerHash {
+            name,
+            hash: Sha256::digest(usda.as_str().as_bytes()).into(),
+        });
+
+        for sublayer in sublayers(usda.as_str()) {
+            let sublayer = resolve_sublayer(Path::new(path), &sublayer.path);
+            self.add_layer(&sublayer.to_string_lossy(), directory, depth + 1)?;
+        }
+
+        Ok(())
+    }
+
+    // A description of the first difference between two base scenes, naming the layer.
+    pub fn mismatch(&self, theirs: &Self) -> Option<String> {
+        for index in 0..self.layers.len().max(theirs.layers.len()) {
+            match (self.layers.get(index), theirs.layers.get(index)) {
+                (Some(ours), Some(theirs)) if ours.name != theirs.name => {
+                    return Some(format!("layer {} is {} for them", ours.name, theirs.name))
+                }
+                (Some(ours), Some(theirs)) if ours.hash != theirs.hash => {
+                    return Some(format!("layer {} has different contents", ours.name))
+                }
+                (Some(ours), None) => return Some(format!("they don't have layer {}", ours.name)),
+                (None, Some(theirs)) => {
+                    return Some(format!("we don't have layer {}", theirs.name))
+                }
+                _ => {}
+            }
+        }
+
+        None
+    }
+}
+
+// An entry in the `subLayers` metadata of a usda layer.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sublayer {
+    pub path: String,
+    // The entry as written, including any layer offset.
+    pub entry: String,
+}
+
+// The index of the `(` that opens the layer metadata and the index just past its `)`.
+fn layer_metadata(source: &[u8]) -> Option<(usize, usize)> {
+    let mut index = 0;
+
+    // The `#usda 1.0` header is skipped as a comment.
+    loop {
+        index = skip_whitespace(source, index);
+        match source.get(index)? {
+            b'#' => index = skip_usda_literal(source, index)?,
+            b'(' => break,
+            _ => return None,
+        }
+    }
+
+    Some((index, usda_closing_bracket(source, index)?))
+}
+
+// The range of the contents of the `subLayers` list in the layer metadata. Only the
+// metadata itself is searched, not anything nested in it or in strings.
+fn sublayer_list(source: &[u8]) -> Option<(usize, usize)> {
+    const SUBLAYERS: &[u8] = b"subLayers";
+
+    let (start, end) = layer_metadata(source)?;
+    let mut index = start + 1;
+
+    while index < end - 1 {
+        if let Some(next) = skip_usda_literal(source, index) {
+            index = next;
+            continue;
+        }
+
+        match source[index] {
+            b'(' | b'[' | b'{' => {
+                index = usda_closing_bracket(source, index)?;
+                continue;
+            }
+            _ if source[index..].starts_with(SUBLAYERS) && !is_identifier(source[index - 1]) => {
+                let equals = skip_whitespace(source, index + SUBLAYERS.len());
+                if source.get(equals) == Some(&b'=') {
+                    let open = skip_whitespace(source, equals + 1);
+                    if source.get(open) == Some(&b'[') {
+                        let close = usda_closing_bracket(source, open)?;
+                        return Some((open + 1, close - 1));
+                    }
+                }
+            }
+            _ => {}
+        }
+        index += 1;
+    }
+
+    None
+}
+
+fn skip_whitespace(source: &[u8], mut index: usize) -> usize {
+    while source.get(index).map_or(false, u8::is_ascii_whitespace) {
+        index += 1;
+    }
+    index
+}
+
+fn is_identifier(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_' || byte == b':'
+}
+
+// The sublayers of a usda layer, strongest first.
+pub fn sublayers(usda: &str) -> Vec<Sublayer> {
+    let source = usda.as_bytes();
+    let (start, end) = match sublayer_list(source) {
+        Some(list) => list,
+        None => return Vec::new(),
+    };
+
+    let mut entries = Vec::new();
+    let mut entry_start = start;
+    let mut index = start;
+    while index <= end {
+        if index == end || source[index] == b',' {
+            entries.push(&usda[entry_start..index]);
+            entry_start = index + 1;
+            index += 1;
+        } else if let Some(next) = skip_usda_literal(source, index) {
+            index = next;
+        } else if matches!(source[index], b'(' | b'[' | b'{') {
+            index = usda_closing_bracket(source, index).unwrap_or(end);
+        } else {
+            index += 1;
+        }
+    }
+
+    entries
+        .into_iter()
+        .filter_map(|entry| {
+            // Comments can come before an entry.
+            let entry = entry.as_bytes();
+            let mut index = 0;
+            while index < entry.len() {
+                if entry[index].is_ascii_whitespace() {
+                    index += 1;
+                } else if entry[index] == b'#' {
+                    index = skip_usda_literal(entry, index)?;
+                } else {
+                    break;
+                }
+            }
+            let entry = std::str::from_utf8(&entry[index..]).ok()?.trim_end();
+            let delimiter = if entry.starts_with("@@@") { 3 } else { 1 };
+            let end = skip_usda_literal(entry.as_bytes(), 0).filter(|_| entry.starts_with('@'))?;
+            Some(Sublayer {
+                path: entry.get(delimiter..end - delimiter)?.to_string(),
+                entry: entry.to_string(),
+            })
+        })
+        .collect()
+}
+
+// Quote a path as a usda asset path.
+pub fn sublayer_entry(path: &str) -> String {
+    if path.contains('@') {
+        format!("@@@{}@@@", path)
+    } else {
+        format!("@{}@", path)
+    }
+}
+
+// The layer metadata of a usda layer, including its parentheses.
+pub fn layer_metadata_text(usda: &str) -> Option<&str> {
+    layer_metadata(usda.as_bytes()).map(|(start, end)| &usda[start..end])
+}
+
+// Replace the layer metadata of a usda layer, or remove it if `metadata` is None.
+pub fn with_layer_metadata(usda: &str, metadata: Option<&str>) -> String {
+    if let Some((start, end)) = layer_metadata(usda.as_bytes()) {
+        return format!(
+            "{}{}{}",
+            &usda[..start],
+            metadata.unwrap_or_default(),
+            &usda[end..]
+        );
+    }
+
+    match metadata {
+        Some(metadata) => {
+            // The first line is the `#usda 1.0` header, which layer metadata has to follow.
+            let (header, rest) = usda.split_once('\n').unwrap_or((usda, ""));
+            format!("{}\n{}\n{}", header, metadata, rest)
+        }
+        None => usda.to_string(),
+    }
+}
+
+// Replace the sublayers of a usda layer with `entries`, strongest first, adding layer
+// metadata if there isn't any.
+pub fn with_sublayers(usda: &str, entries: &[String]) -> String {
+    let list: String = entries
+        .iter()
+        .enumerate()
+        .map(|(index, entry)| {
+            let separator = if index + 1 < entries.len() { "," } else { "" };
+            format!("\n        {}{}", entry, separator)
+        })
+        .collect();
+
+    let source = usda.as_bytes();
+    if let Some((start, end)) = sublayer_list(source) {
+        return format!("{}{}\n    {}", &usda[..start], list, &usda[end..]);
+    }
+
+    if let Some((start, _)) = layer_metadata(source) {
+        return format!(
+            "{}\n    subLayers = [{}\n    ]{}",
+            &usda[..start + 1],
+            list,
+            &usda[start + 1..]
+        );
+    }
+
+    // The first line is the `#usda 1.0` header, which layer metadata has to follow.
+    let (header, rest) = usda.split_once('\n').unwrap_or((usda, ""));
+    format!(
+        "{}\n(\n    subLayers = [{}\n    ]\n)\n{}",
+        header, list, rest
+    )
+}
+
+// Where a sublayer of the layer at `layer` is. Relative paths are relative to the layer,
+// and `.` and `..` are resolved so that the same layer always has the same path.
+pub fn resolve_sublayer(layer: &Path, sublayer: &str) -> PathBuf {
+    let mut path = PathBuf::new();
+    for component in layer
+        .parent()
+        .unwrap_or(Path::new(""))
+        .join(sublayer)
+        .components()
+    {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir
+                if matches!(path.components().next_back(), Some(Component::Normal(_))) =>
+            {
+                path.pop();
+            }
+            component => path.push(component),
+        }
+    }
+    path
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const USDA: &str = r#"#usda 1.0
+(
+    doc = "subLayers = [@not_a_sublayer.usda@]"
+    subLayers = [
+        @./a.usda@ (offset = 10; scale = 2),
+        # A comment.
+        @b, c.usda@,
+        @@@d@e.usda@@@
+    ]
+)
+
+def "Prim" (
+    subLayers = [@not_a_sublayer.usda@]
+)
+{
+}
+"#;
+
+    #[test]
+    fn only_layer_metadata_is_read() {
+        let sublayers = sublayers(USDA);
+        let paths: Vec<&str> = sublayers
+            .iter()
+            .map(|sublayer| sublayer.path.as_str())
+            .collect();
+        assert_eq!(paths, ["./a.usda", "b, c.usda", "d@e.usda"]);
+        assert_eq!(sublayers[0].entry, "@./a.usda@ (offset = 10; scale = 2)");
+    }
+
+    #[test]
+    fn rewriting_sublayers_keeps_offsets() {
+        let entries: Vec<String> = std::iter::once(sublayer_entry("./new@.usda"))
+            .chain(sublayers(USDA).into_iter().map(|sublayer| sublayer.entry))
+            .collect();
+        let rewritten = with_sublayers(USDA, &entries);
+
+        assert_eq!(sublayers(&rewritten)[0].path, "./new@.usda");
+        assert_eq!(sublayers(&rewritten)[1..], sublayers(USDA)[..]);
+        assert!(rewritten.ends_with(&USDA[USDA.find("\ndef").unwrap()..]));
+
+        assert!(sublayers(&with_sublayers(USDA, &[])).is_empty());
+    }
+
+    #[test]
+    fn metadata_is_added_if_missing() {
+        for usda in [
+            "#usda 1.0\n\ndef \"Prim\"\n{\n}\n",
+            "#usda 1.0\n(\n    defaultPrim = \"Prim\"\n)\n",
+        ] {
+            let entries = [sublayer_entry("a.usda")];
+            assert_eq!(sublayers(&with_sublayers(usda, &entries))[0].path, "a.usda");
+        }
+    }
+
+    #[test]
+    fn sublayers_resolve_relative_to_their_layer() {
+        let resolve = |layer: &str, sublayer| resolve_sublayer(Path::new(layer), sublayer);
+        assert_eq!(
+            resolve("scene/a.usda", "./b/../c.usda"),
+            Path::new("scene/c.usda")
+        );
+        assert_eq!(resolve("a.usda", "../b.usda"), Path::new("../b.usda"));
+        assert_eq!(resolve("scene/a.usda", "/b.usda"), Path::new("/b.usda"));
+    }
+}
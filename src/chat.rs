@@ -0,0 +1,140 @@
+use iroh_net::key::PublicKey;
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::broadcast;
+
+// Older messages are dropped once there are this many.
+const MAX_HISTORY: usize = 500;
+// In bytes. Longer messages are cut short.
+const MAX_MESSAGE_LEN: usize = 2000;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ChatMessage {
+    pub text: String,
+    // Milliseconds since the unix epoch, according to the sender.
+    pub sent_at: u64,
+}
+
+impl ChatMessage {
+    pub fn new(text: String) -> Self {
+        Self {
+            text,
+            sent_at: std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .map(|duration| duration.as_millis() as u64)
+                .unwrap_or_default(),
+        }
+    }
+
+    // Peers can send anything, so control characters and newlines are escaped to keep one
+    // message to one log line.
+    pub fn escaped_text(&self) -> String {
+        self.text.escape_debug().to_string()
+    }
+
+    fn truncate(&mut self) {
+        if self.text.len() <= MAX_MESSAGE_LEN {
+            return;
+        }
+
+        let mut end = MAX_MESSAGE_LEN;
+        while !self.text.is_char_boundary(end) {
+            end -= 1;
+        }
+        self.text.truncate(end);
+    }
+
+    // HH:MM:SS in UTC.
+    pub fn format_time(&self) -> String {
+        let seconds = self.sent_at / 1000;
+        format!(
+            "{:02}:{:02}:{:02}",
+            (seconds / 3600) % 24,
+            (seconds / 60) % 60,
+            seconds % 60
+        )
+    }
+}
+
+pub struct ChatEntry {
+    pub sender: PublicKey,
+    pub message: ChatMessage,
+}
+
+#[derive(Clone)]
+pub struct Chat {
+    history: Arc<Mutex<VecDeque<ChatEntry>>>,
+    outgoing: broadcast::Sender<ChatMessage>,
+}
+
+impl Default for Chat {
+    fn default() -> Self {
+        Self {
+            history: Default::default(),
+            outgoing: broadcast::channel(100).0,
+        }
+    }
+}
+
+impl Chat {
+    // Record a message of our own and send it to every connection.
+    pub fn send(&self, sender: PublicKey, mut message: ChatMessage) {
+        message.truncate();
+        // Errors if there are no connections, which is fine.
+        let _ = self.outgoing.send(message.clone());
+        self.receive(sender, message);
+    }
+
+    pub fn receive(&self, sender: PublicKey, mut message: ChatMessage) {
+        message.truncate();
+
+        let mut history = self.history.lock().unwrap();
+        if history.len() >= MAX_HISTORY {
+            history.pop_front();
+        }
+        history.push_back(ChatEntry { sender, message });
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<ChatMessage> {
+        self.outgoing.subscribe()
+    }
+
+    pub fn history(&self) -> MutexGuard<VecDeque<ChatEntry>> {
+        self.history.lock().unwrap()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh_net::key::SecretKey;
+
+    #[test]
+    fn history_and_messages_are_capped() {
+        let chat = Chat::default();
+        let sender = SecretKey::generate().public();
+
+        for index in 0..MAX_HISTORY + 10 {
+            chat.receive(sender, ChatMessage::new(index.to_string()));
+        }
+        let history = chat.history();
+        assert_eq!(history.len(), MAX_HISTORY);
+        assert_eq!(history[0].message.text, "10");
+        drop(history);
+
+        // Cut at a character boundary.
+        chat.receive(
+            sender,
+            ChatMessage::new(format!("a{}", "é".repeat(MAX_MESSAGE_LEN))),
+        );
+        let text = &chat.history().back().unwrap().message.text;
+        assert_eq!(text.len(), MAX_MESSAGE_LEN - 1);
+    }
+
+    #[test]
+    fn newlines_are_escaped() {
+        let message = ChatMessage::new("one\ntwo\x1b[2J".to_string());
+        assert_eq!(message.escaped_text(), "one\\ntwo\\u{1b}[2J");
+    }
+}
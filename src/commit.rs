@@ -0,0 +1,458 @@
+use crate::acl;
+use crate::assets::{self, AssetCache};
+use crate::base_scene::{self, sublayers, with_sublayers};
+use crate::export;
+use crate::networking;
+use crate::util::{is_usda_prim_start, usda_closing_bracket, usda_prim_header, usda_statement_end};
+use iroh_net::key::PublicKey;
+use std::collections::BTreeSet;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+// How collaborative edits are written back into the base scene.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum CommitMode {
+    // The merged edits go in a new file that's added as the strongest sublayer of the base.
+    NewSublayer,
+    // The merged edits are flattened into the base layer itself.
+    Flatten,
+}
+
+impl CommitMode {
+    pub const ALL: [Self; 2] = [Self::NewSublayer, Self::Flatten];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::NewSublayer => "new sublayer",
+            Self::Flatten => "flatten into base",
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct CommitOptions {
+    pub ours: bool,
+    pub authors: BTreeSet<PublicKey>,
+    pub mode: CommitMode,
+}
+
+impl Default for CommitOptions {
+    fn default() -> Self {
+        Self {
+            ours: true,
+            authors: BTreeSet::new(),
+            mode: CommitMode::NewSublayer,
+        }
+    }
+}
+
+pub struct PrimChange {
+    pub path: String,
+    // Whether the edits define the prim rather than only override it.
+    pub defined: bool,
+    pub properties: Vec<String>,
+}
+
+// The merged edits, kept so that what's committed is exactly what was previewed.
+pub struct CommitPreview {
+    // Whose edits were merged.
+    ours: bool,
+    authors: BTreeSet<PublicKey>,
+    pub layer_count: usize,
+    pub usda: String,
+    pub changes: Vec<PrimChange>,
+}
+
+impl CommitPreview {
+    // Whether the preview is of the edits the options choose. A preview can finish after
+    // the options have changed.
+    pub fn is_for(&self, options: &CommitOptions) -> bool {
+        self.ours == options.ours && self.authors == options.authors
+    }
+}
+
+// Merge the chosen layers into one and list the prims and properties they change.
+pub async fn preview(
+    state: &networking::State,
+    options: &CommitOptions,
+) -> anyhow::Result<CommitPreview> {
+    // Avatars only make sense in a session, so they're left out of the base scene.
+    let layers: Vec<(String, String)> = export::edit_layers(state, options.ours, |author| {
+        options.authors.contains(author)
+    })
+    .await
+    .into_iter()
+    .map(|(name, usda)| (name, acl::remove_prims(&usda, "/avatars")))
+    .collect();
+
+    if layers.is_empty() {
+        return Err(anyhow::anyhow!("There are no edits to commit"));
+    }
+
+    let usda = merge(&layers, None)?;
+
+    Ok(CommitPreview {
+        ours: options.ours,
+        authors: options.authors.clone(),
+        layer_count: layers.len(),
+        changes: changes(&usda),
+        usda,
+    })
+}
+
+// Compose layers over an optional base and read the result back as usda.
+fn merge(layers: &[(String, String)], base: Option<&str>) -> anyhow::Result<String> {
+    let temporary = export::temporary_path("merged.usda");
+    let result = export::export_layers(layers, base, &temporary)
+        .and_then(|_| Ok(std::fs::read_to_string(&temporary)?));
+    let _ = std::fs::remove_file(&temporary);
+    result
+}
+
+// Write the previewed edits into the base scene. `sublayer` is where the new sublayer goes
+// when committing as one.
+pub fn commit(
+    base: &str,
+    preview: &CommitPreview,
+    mode: CommitMode,
+    sublayer: Option<&Path>,
+    cache: &AssetCache,
+) -> anyhow::Result<()> {
+    let base_path = Path::new(base);
+    // The sublayer list is edited as text, which usdc doesn't allow.
+    if base_path
+        .extension()
+        .map_or(true, |extension| extension != "usda")
+    {
+        return Err(anyhow::anyhow!(
+            "Can only commit into a usda base layer, not {}",
+            base
+        ));
+    }
+
+    let base_usda = std::fs::read_to_string(base_path)?;
+    let directory = base_path.parent().unwrap_or(Path::new(""));
+
+    let committed = match mode {
+        CommitMode::NewSublayer => {
+            let sublayer =
+                sublayer.ok_or_else(|| anyhow::anyhow!("No file chosen for the new sublayer"))?;
+            let edits = copy_cached_assets(
+                &preview.usda,
+                cache,
+                sublayer.parent().unwrap_or(Path::new("")),
+            )?;
+            std::fs::write(sublayer, edits)?;
+
+            // Relative to the base where possible, so the scene can still be moved.
+            let sublayer = std::fs::canonicalize(sublayer)?;
+            let sublayer_path = match std::fs::canonicalize(directory)
+                .ok()
+                .and_then(|directory| sublayer.strip_prefix(directory).ok().map(Path::to_owned))
+            {
+                Some(relative) => format!("./{}", relative.display()),
+                None => sublayer.display().to_string(),
+            };
+
+            add_sublayer(&base_usda, &sublayer_path.replace('\\', "/"))
+        }
+        CommitMode::Flatten => {
+            let edits = copy_cached_assets(&preview.usda, cache, directory)?;
+
+            // Only the base layer's own opinions are flattened, so its sublayers are taken
+            // out and put back after. The copy sits next to the base so that relative
+            // paths in it still resolve.
+            let (own, mut file) = create_sibling(base_path, "commit.usda")?;
+            let flattened = file
+                .write_all(with_sublayers(&base_usda, &[]).as_bytes())
+                .map_err(anyhow::Error::from)
+                .and_then(|_| {
+                    merge(
+                        &[("edits".to_string(), edits)],
+                        Some(&own.to_string_lossy()),
+                    )
+                });
+            drop(file);
+            let _ = std::fs::remove_file(&own);
+
+            keep_base_metadata(&base_usda, &flattened?)
+        }
+    };
+
+    // The base is only ever overwritten with a copy of the original next to it. Each commit
+    // gets its own, so that earlier originals are kept.
+    let seconds = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    let (backup, mut file) = create_sibling(base_path, &format!("{}.usda.bak", seconds))?;
+    file.write_all(base_usda.as_bytes())?;
+    std::fs::write(base_path, committed)?;
+
+    log::info!(
+        "Committed {} layers into {} as a {}, backing up the original to {}. Peers will need \
+         the updated base scene",
+        preview.layer_count,
+        base,
+        mode.as_str(),
+        backup.display()
+    );
+
+    Ok(())
+}
+
+// A new file next to `path` that nothing else is using, named with `suffix`.
+fn create_sibling(path: &Path, suffix: &str) -> anyhow::Result<(PathBuf, std::fs::File)> {
+    let mut attempt = 0_u32;
+    loop {
+        let sibling = path.with_extension(format!("{}.{}", attempt, suffix));
+        match std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&sibling)
+        {
+            Ok(file) => return Ok((sibling, file)),
+            Err(error) if error.kind() == std::io::ErrorKind::AlreadyExists => attempt += 1,
+            Err(error) => return Err(error.into()),
+        }
+    }
+}
+
+// Flattening composes from an anonymous layer, so the base layer's own metadata, including
+// its sublayers, is put back.
+fn keep_base_metadata(base_usda: &str, flattened: &str) -> String {
+    base_scene::with_layer_metadata(flattened, base_scene::layer_metadata_text(base_usda))
+}
+
+// Peers' assets resolve into the session's asset cache, which doesn't outlive it. Copy the
+// ones a layer uses into `directory`, pointing the layer at the copies.
+fn copy_cached_assets(usda: &str, cache: &AssetCache, directory: &Path) -> anyhow::Result<String> {
+    let mut error = None;
+
+    let usda = assets::replace_asset_paths(usda, |asset_path| {
+        let relative = Path::new("assets").join(cache.path_in_cache(asset_path)?);
+        let copy = directory.join(&relative);
+        let result =
+            std::fs::create_dir_all(copy.parent()?).and_then(|_| std::fs::copy(asset_path, &copy));
+
+        if let Err(copy_error) = result {
+            error.get_or_insert_with(|| {
+                anyhow::anyhow!("Copying asset {} failed: {}", asset_path, copy_error)
+            });
+            return None;
+        }
+
+        Some(format!(
+            "./{}",
+            relative.to_string_lossy().replace('\\', "/")
+        ))
+    });
+
+    match error {
+        Some(error) => Err(error),
+        None => Ok(usda),
+    }
+}
+
+// Add a path to the front of a usda layer's sublayers, making it the strongest.
+fn add_sublayer(usda: &str, path: &str) -> String {
+    let entries: Vec<String> = std::iter::once(base_scene::sublayer_entry(path))
+        .chain(sublayers(usda).into_iter().map(|sublayer| sublayer.entry))
+        .collect();
+    with_sublayers(usda, &entries)
+}
+
+// The prims in a usda layer and the properties authored on each, in order.
+fn changes(usda: &str) -> Vec<PrimChange> {
+    let mut changes = Vec::new();
+    prim_changes(usda.as_bytes(), 0, usda.len(), None, &mut changes);
+    changes
+}
+
+// Add the prims between `index` and `end` to `changes`, and the properties there to the prim
+// at `parent`.
+fn prim_changes(
+    source: &[u8],
+    mut index: usize,
+    end: usize,
+    parent: Option<usize>,
+    changes: &mut Vec<PrimChange>,
+) {
+    while index < end {
+        if source[index].is_ascii_whitespace() {
+            index += 1;
+            continue;
+        }
+
+        if is_usda_prim_start(source, index) {
+            if let Some((body_start, name)) = usda_prim_header(source, index, end) {
+                let body_end = usda_closing_bracket(source, body_start)
+                    .map_or(end, |body_end| body_end.min(end));
+                let parent_path = parent.map_or("", |parent| changes[parent].path.as_str());
+                let prim = changes.len();
+                changes.push(PrimChange {
+                    path: format!("{}/{}", parent_path, name),
+                    defined: !source[index..].starts_with(b"over"),
+                    properties: Vec::new(),
+                });
+
+                prim_changes(source, body_start + 1, body_end - 1, Some(prim), changes);
+                index = body_end;
+                continue;
+            }
+        }
+
+        let statement_end = usda_statement_end(source, index, end);
+        let property = parent.zip(property_name(&source[index..statement_end]));
+        if let Some((parent, name)) = property {
+            let properties = &mut changes[parent].properties;
+            if !properties.contains(&name) {
+                properties.push(name);
+            }
+        }
+        index = statement_end;
+    }
+}
+
+// The property a statement in a prim's body authors, if it's one that does.
+fn property_name(statement: &[u8]) -> Option<String> {
+    let statement = std::str::from_utf8(statement).ok()?.trim();
+    if statement.starts_with('#') || statement.starts_with("reorder ") {
+        return None;
+    }
+
+    // The name comes before the value and any metadata.
+    let declaration = statement
+        .find(['=', '('])
+        .map_or(statement, |index| &statement[..index]);
+    let name = declaration.split_whitespace().last()?;
+    // Variant sets have quoted names and aren't properties.
+    if name.starts_with('"') {
+        return None;
+    }
+
+    Some(
+        name.trim_end_matches(".timeSamples")
+            .trim_end_matches(".connect")
+            .to_string(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn changes_list_prims_and_their_properties() {
+        let usda = r#"#usda 1.0
+(
+    defaultPrim = "World"
+)
+
+def Xform "World" (
+    kind = "group"
+)
+{
+    double3 xformOp:translate.timeSamples = {
+        0: (0, 0, 0),
+        1: (1, 0, 0),
+    }
+    double3 xformOp:translate = (0, 0, 0)
+    string text = "} { def \"Not\" {"
+    # A comment } with { braces
+    string multiline = """
+} {
+def "Not"
+{
+"""
+
+    over "Child" (
+        doc = "a = b"
+    )
+    {
+        color3f inputs:color.connect = </World/Shader.outputs:color>
+    }
+
+    variantSet "look" = {
+        "red" {
+        }
+    }
+}
+
+def "Other"
+{
+}
+"#;
+
+        let changes = changes(usda);
+        let summary: Vec<(&str, bool, Vec<&str>)> = changes
+            .iter()
+            .map(|change| {
+                (
+                    change.path.as_str(),
+                    change.defined,
+                    change.properties.iter().map(String::as_str).collect(),
+                )
+            })
+            .collect();
+
+        assert_eq!(
+            summary,
+            [
+                (
+                    "/World",
+                    true,
+                    vec!["xformOp:translate", "text", "multiline"]
+                ),
+                ("/World/Child", false, vec!["inputs:color"]),
+                ("/Other", true, vec![]),
+            ]
+        );
+    }
+
+    #[test]
+    fn flattening_keeps_base_metadata() {
+        let base = r#"#usda 1.0
+(
+    defaultPrim = "World"
+    upAxis = "Z"
+    subLayers = [
+        @./a.usda@ (offset = 10)
+    ]
+)
+
+def "World"
+{
+}
+"#;
+        let flattened = "#usda 1.0\n(\n    doc = \"\"\"Generated from Composed Stage\"\"\"\n)\n\ndef \"World\"\n{\n    float size = 1\n}\n";
+
+        let committed = keep_base_metadata(base, flattened);
+
+        assert_eq!(
+            base_scene::layer_metadata_text(&committed),
+            base_scene::layer_metadata_text(base)
+        );
+        assert!(committed.contains("defaultPrim = \"World\""));
+        assert!(committed.contains("upAxis = \"Z\""));
+        assert_eq!(sublayers(&committed), sublayers(base));
+        assert!(committed.ends_with("def \"World\"\n{\n    float size = 1\n}\n"));
+
+        let without_metadata = "#usda 1.0\n\ndef \"World\"\n{\n}\n";
+        assert_eq!(
+            base_scene::layer_metadata_text(&keep_base_metadata(base, without_metadata)),
+            base_scene::layer_metadata_text(base)
+        );
+    }
+
+    #[test]
+    fn added_sublayers_are_strongest() {
+        let usda = "#usda 1.0\n(\n    subLayers = [\n        @./a.usda@ (offset = 10)\n    ]\n)\n";
+        let paths: Vec<String> = sublayers(&add_sublayer(usda, "./new.usda"))
+            .into_iter()
+            .map(|sublayer| sublayer.entry)
+            .collect();
+        assert_eq!(paths, ["@./new.usda@", "@./a.usda@ (offset = 10)"]);
+    }
+}
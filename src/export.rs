@@ -0,0 +1,514 @@
+use crate::assets;
+use crate::base_scene::sublayers;
+use crate::networking;
+use bbl_usd::{cpp, sdf, usd};
+use iroh_net::key::PublicKey;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExportFormat {
+    Usda,
+    Usdc,
+    Usdz,
+}
+
+impl ExportFormat {
+    pub const ALL: [Self; 3] = [Self::Usda, Self::Usdc, Self::Usdz];
+
+    pub fn extension(&self) -> &'static str {
+        match self {
+            Self::Usda => "usda",
+            Self::Usdc => "usdc",
+            Self::Usdz => "usdz",
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ExportMode {
+    // Everything composed into a single layer.
+    Flattened,
+    // Our layers and each peer's layers as separate files, sublayered over the base.
+    LayerStack,
+    // Our layers and each peer's layers composed, without the base.
+    EditsOnly,
+}
+
+impl ExportMode {
+    pub const ALL: [Self; 3] = [Self::Flattened, Self::LayerStack, Self::EditsOnly];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Flattened => "flattened",
+            Self::LayerStack => "layer stack",
+            Self::EditsOnly => "edits only",
+        }
+    }
+
+    // Separate layers are written as usda, so the stack can only be packaged as usdz.
+    pub fn supports(&self, format: ExportFormat) -> bool {
+        *self != Self::LayerStack || format != ExportFormat::Usdc
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct ExportOptions {
+    pub format: ExportFormat,
+    pub mode: ExportMode,
+}
+
+impl Default for ExportOptions {
+    fn default() -> Self {
+        Self {
+            format: ExportFormat::Usdc,
+            mode: ExportMode::Flattened,
+        }
+    }
+}
+
+// The layers of the collaborative edits, strongest first, with a name for each. Our layers
+// are included if `ours` is set and peers' if `include_author` returns true for them.
+pub async fn edit_layers(
+    state: &networking::State,
+    ours: bool,
+    include_author: impl Fn(&PublicKey) -> bool,
+) -> Vec<(String, String)> {
+    // Ours and each author's layers are ordered the way they're sublayered on the stage.
+    let (order, local_root) = {
+        let usd = state.usd.read().await;
+        let order: Vec<String> = match usd.root_layer.export_to_string() {
+            Ok(usda) => sublayers(usda.as_str())
+                .into_iter()
+                .map(|sublayer| sublayer.path)
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        (order, usd.local_root.clone())
+    };
+    let position = |identifier: &str| {
+        order
+            .iter()
+            .position(|sublayer| sublayer == identifier)
+            .unwrap_or(order.len())
+    };
+
+    let mut groups: Vec<(usize, Vec<(String, String)>)> = Vec::new();
+
+    if ours {
+        groups.push((
+            position(&local_root),
+            state
+                .state
+                .borrow()
+                .layers
+                .iter()
+                .enumerate()
+                .rev()
+                .map(|(index, layer)| (format!("local_{}", index), layer.as_str().to_string()))
+                .collect(),
+        ));
+    }
+
+    let mut remote_authors = Vec::new();
+    state
+        .remote_authors
+        .scan_async(|author, remote_author| {
+            if include_author(author) {
+                remote_authors.push((*author, remote_author.clone()))
+            }
+        })
+        .await;
+
+    for (author, remote_author) in remote_authors {
+        let author_layers = remote_author.layers.lock().await;
+        let layers = author_layers
+            .sublayers
+            .iter()
+            .enumerate()
+            .rev()
+            .filter_map(|(index, sublayer)| {
+                let usda = sublayer.export_to_string().ok()?;
+                Some((
+                    format!("{}_{}", author.fmt_short(), index),
+                    usda.as_str().to_string(),
+                ))
+            })
+            .collect();
+        groups.push((
+            position(&remote_author.root.get_identifier().to_string()),
+            layers,
+        ));
+    }
+
+    groups.sort_by_key(|(position, _)| *position);
+    groups.into_iter().flat_map(|(_, layers)| layers).collect()
+}
+
+pub fn temporary_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!("usd-render-export-{}-{}", std::process::id(), name))
+}
+
+// Export a stage, flattened, to a file whose format is given by its extension.
+fn export_stage(stage: &usd::StageRefPtr, path: &Path) -> anyhow::Result<()> {
+    let _ = std::fs::remove_file(path);
+    stage.export(&cpp::String::new(&path.to_string_lossy()));
+    if !path.exists() {
+        return Err(anyhow::anyhow!("Exporting to {} failed", path.display()));
+    }
+    Ok(())
+}
+
+// Compose layers in a stage of their own and export them flattened.
+pub fn export_layers(
+    layers: &[(String, String)],
+    base: Option<&str>,
+    path: &Path,
+) -> anyhow::Result<()> {
+    let stage = usd::Stage::create_in_memory();
+    let root_layer = stage.get_root_layer();
+
+    // Sublayers have to stay alive until the stage is exported.
+    let mut sublayers = Vec::new();
+    for (name, usda) in layers {
+        let sublayer = sdf::Layer::create_anonymous(".usda");
+        if !sublayer.import_from_str(&cpp::String::new(usda)) {
+            return Err(anyhow::anyhow!("Import of {} failed", name));
+        }
+        sublayers.push(sublayer);
+    }
+    if let Some(base) = base {
+        sublayers.push(sdf::Layer::find_or_open(base));
+    }
+
+    // Inserting each at the front leaves the first one the strongest.
+    for sublayer in sublayers.iter().rev() {
+        root_layer.insert_sub_layer_path(sublayer.get_identifier(), 0);
+    }
+
+    export_stage(&stage, path)
+}
+
+// The files that layers in a usdz package reference, copied into it so that it's self
+// contained.
+#[derive(Default)]
+struct PackagedAssets {
+    files: Vec<(String, Vec<u8>)>,
+    // The name in the package of each file, by where it came from.
+    names: HashMap<PathBuf, String>,
+    missing: HashSet<String>,
+}
+
+impl PackagedAssets {
+    // Point the asset paths of a layer that's `depth` directories into the package at
+    // packaged copies of the files. Paths that aren't readable files are left as they are.
+    fn package(&mut self, usda: &str, depth: usize) -> String {
+        let prefix = if depth == 0 {
+            "./".to_string()
+        } else {
+            "../".repeat(depth)
+        };
+
+        assets::replace_asset_paths(usda, |asset_path| {
+            let source = Path::new(asset_path);
+            if let Some(name) = self.names.get(source) {
+                return Some(format!("{}{}", prefix, name));
+            }
+
+            let data = match std::fs::read(source) {
+                Ok(data) => data,
+                Err(error) => {
+                    if self.missing.insert(asset_path.to_string()) {
+                        log::warn!("Not packaging asset {}: {}", asset_path, error);
+                    }
+                    return None;
+                }
+            };
+
+            let file_name = source.file_name()?.to_string_lossy().replace('@', "_");
+            let name = format!("assets/{}_{}", self.names.len(), file_name);
+            self.files.push((name.clone(), data));
+            self.names.insert(source.to_owned(), name.clone());
+            Some(format!("{}{}", prefix, name))
+        })
+    }
+}
+
+// Export to a temporary usda file and read it back.
+fn export_usda(
+    name: &str,
+    export: impl FnOnce(&Path) -> anyhow::Result<()>,
+) -> anyhow::Result<String> {
+    let temporary = temporary_path(name);
+    let result = export(&temporary).and_then(|_| Ok(std::fs::read_to_string(&temporary)?));
+    let _ = std::fs::remove_file(&temporary);
+    result
+}
+
+// Usd can't write usdz itself, so export usda and package it with the assets it uses.
+fn export_as(
+    path: &Path,
+    format: ExportFormat,
+    export: impl FnOnce(&Path) -> anyhow::Result<()>,
+) -> anyhow::Result<()> {
+    if format != ExportFormat::Usdz {
+        return export(path);
+    }
+
+    let mut assets = PackagedAssets::default();
+    let scene = assets.package(&export_usda("scene.usda", export)?, 0);
+    let mut files = vec![("scene.usda".to_string(), scene.into_bytes())];
+    files.append(&mut assets.files);
+    write_usdz(path, &files)
+}
+
+// A root layer that sublayers each edit layer, written next to it, over the base.
+fn export_layer_stack(
+    layers: &[(String, String)],
+    base: &str,
+    path: &Path,
+    format: ExportFormat,
+) -> anyhow::Result<()> {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy().into_owned())
+        .unwrap_or_else(|| "export".to_string());
+    let directory = format!("{}_layers", stem);
+
+    let mut assets = PackagedAssets::default();
+    let mut files: Vec<(String, Vec<u8>)> = layers
+        .iter()
+        .map(|(name, usda)| {
+            let usda = if format == ExportFormat::Usdz {
+                assets.package(usda, 1)
+            } else {
+                usda.clone()
+            };
+            (format!("{}/{}.usda", directory, name), usda.into_bytes())
+        })
+        .collect();
+
+    let mut root = String::from("#usda 1.0\n(\n    subLayers = [\n");
+    for (file, _) in &files {
+        root.push_str(&format!("        @./{}@,\n", file));
+    }
+
+    // A usdz package has to be self contained, so the base goes in it, flattened, along with
+    // the assets that it and the layers use.
+    let base_path = if format == ExportFormat::Usdz {
+        let base_path = format!("{}/base.usda", directory);
+        let base_usda = export_usda("base.usda", |path| export_layers(&[], Some(base), path))?;
+        files.push((
+            base_path.clone(),
+            assets.package(&base_usda, 1).into_bytes(),
+        ));
+        files.append(&mut assets.files);
+        format!("./{}", base_path)
+    } else {
+        std::fs::canonicalize(base)?
+            .to_string_lossy()
+            .replace('\\', "/")
+    };
+    root.push_str(&format!("        @{}@\n    ]\n)\n", base_path));
+
+    if format == ExportFormat::Usdz {
+        files.insert(0, ("scene.usda".to_string(), root.into_bytes()));
+        return write_usdz(path, &files);
+    }
+
+    let parent = path.parent().unwrap_or(Path::new(""));
+    std::fs::create_dir_all(parent.join(&directory))?;
+    for (file, data) in &files {
+        std::fs::write(parent.join(file), data)?;
+    }
+    std::fs::write(path, root)?;
+
+    Ok(())
+}
+
+pub async fn export(
+    state: &networking::State,
+    base: &str,
+    path: &Path,
+    options: ExportOptions,
+) -> anyhow::Result<()> {
+    if !options.mode.supports(options.format) {
+        return Err(anyhow::anyhow!(
+            "A {} can't be exported as {}",
+            options.mode.as_str(),
+            options.format.extension()
+        ));
+    }
+
+    // A snapshot of the edit layers, so that nothing is locked while writing. Flattening
+    // composes them over the base again, which leaves out the local only layers on the stage.
+    let layers = edit_layers(state, true, |_| true).await;
+
+    let result = tokio::task::spawn_blocking({
+        let base = base.to_string();
+        let path = path.to_owned();
+        move || match options.mode {
+            ExportMode::Flattened => export_as(&path, options.format, |path| {
+                export_layers(&layers, Some(&base), path)
+            }),
+            ExportMode::LayerStack => export_layer_stack(&layers, &base, &path, options.format),
+            ExportMode::EditsOnly => export_as(&path, options.format, |path| {
+                export_layers(&layers, None, path)
+            }),
+        }
+    })
+    .await?;
+
+    if result.is_ok() {
+        log::info!(
+            "Exported {} {} to {}",
+            options.mode.as_str(),
+            options.format.extension(),
+            path.display()
+        );
+    }
+
+    result
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0_u32;
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+// A usdz package is an uncompressed zip file with each file's data aligned to 64 bytes.
+fn write_usdz(path: &Path, files: &[(String, Vec<u8>)]) -> anyhow::Result<()> {
+    const ALIGNMENT: usize = 64;
+    // 1980-01-01, the earliest date a zip file can have.
+    const DATE: u16 = (1 << 5) | 1;
+
+    let mut output = Vec::new();
+    let mut central_directory = Vec::new();
+
+    for (name, data) in files {
+        let offset = output.len();
+        let crc = crc32(data);
+
+        // Padding goes in an extra field, which needs at least a 4 byte header.
+        let mut padding = (ALIGNMENT - (offset + 30 + name.len()) % ALIGNMENT) % ALIGNMENT;
+        if padding > 0 && padding < 4 {
+            padding += ALIGNMENT;
+        }
+
+        let fixed = |buffer: &mut Vec<u8>| {
+            buffer.extend_from_slice(&20_u16.to_le_bytes());
+            buffer.extend_from_slice(&0_u16.to_le_bytes());
+            buffer.extend_from_slice(&0_u16.to_le_bytes());
+            buffer.extend_from_slice(&0_u16.to_le_bytes());
+            buffer.extend_from_slice(&DATE.to_le_bytes());
+            buffer.extend_from_slice(&crc.to_le_bytes());
+            buffer.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            buffer.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            buffer.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        };
+
+        output.extend_from_slice(&0x0403_4b50_u32.to_le_bytes());
+        fixed(&mut output);
+        output.extend_from_slice(&(padding as u16).to_le_bytes());
+        output.extend_from_slice(name.as_bytes());
+        if padding > 0 {
+            output.extend_from_slice(&0x1986_u16.to_le_bytes());
+            output.extend_from_slice(&(padding as u16 - 4).to_le_bytes());
+            output.resize(output.len() + padding - 4, 0);
+        }
+        output.extend_from_slice(data);
+
+        central_directory.extend_from_slice(&0x0201_4b50_u32.to_le_bytes());
+        central_directory.extend_from_slice(&20_u16.to_le_bytes());
+        fixed(&mut central_directory);
+        // Extra field, comment, disk, internal and external attributes.
+        central_directory.extend_from_slice(&[0; 12]);
+        central_directory.extend_from_slice(&(offset as u32).to_le_bytes());
+        central_directory.extend_from_slice(name.as_bytes());
+    }
+
+    let central_directory_offset = output.len();
+    output.extend_from_slice(&central_directory);
+
+    output.extend_from_slice(&0x0605_4b50_u32.to_le_bytes());
+    output.extend_from_slice(&[0; 4]);
+    output.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    output.extend_from_slice(&(files.len() as u16).to_le_bytes());
+    output.extend_from_slice(&(central_directory.len() as u32).to_le_bytes());
+    output.extend_from_slice(&(central_directory_offset as u32).to_le_bytes());
+    output.extend_from_slice(&0_u16.to_le_bytes());
+
+    std::fs::write(path, output)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn u16_at(data: &[u8], offset: usize) -> usize {
+        u16::from_le_bytes([data[offset], data[offset + 1]]) as usize
+    }
+
+    fn u32_at(data: &[u8], offset: usize) -> u32 {
+        u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap())
+    }
+
+    #[test]
+    fn crc32_matches_zip() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn usdz_files_are_stored_and_aligned() {
+        let files: Vec<(String, Vec<u8>)> = (0..4)
+            .map(|index| {
+                (
+                    format!("layers/{}.usda", "a".repeat(index * 7)),
+                    vec![index as u8; index * 13 + 1],
+                )
+            })
+            .collect();
+
+        let path = temporary_path("test.usdz");
+        write_usdz(&path, &files).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        let _ = std::fs::remove_file(&path);
+
+        let end = data.len() - 22;
+        assert_eq!(u32_at(&data, end), 0x0605_4b50);
+        assert_eq!(u16_at(&data, end + 10), files.len());
+        let mut central = u32_at(&data, end + 16) as usize;
+
+        for (name, contents) in &files {
+            assert_eq!(u32_at(&data, central), 0x0201_4b50);
+            let name_length = u16_at(&data, central + 28);
+            assert_eq!(
+                &data[central + 46..central + 46 + name_length],
+                name.as_bytes()
+            );
+
+            let local = u32_at(&data, central + 42) as usize;
+            assert_eq!(u32_at(&data, local), 0x0403_4b50);
+            // Stored without compression.
+            assert_eq!(u16_at(&data, local + 8), 0);
+            assert_eq!(u32_at(&data, local + 14), crc32(contents));
+
+            let start = local + 30 + name_length + u16_at(&data, local + 28);
+            assert_eq!(start % 64, 0);
+            assert_eq!(&data[start..start + contents.len()], &contents[..]);
+
+            central += 46 + name_length;
+        }
+    }
+}
@@ -0,0 +1,80 @@
+use crate::avatars::{self, AvatarPose, AvatarPoses, LayerPoses};
+use crate::util::millis_since_start;
+use iroh_net::key::PublicKey;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+
+pub type Interests = Arc<scc::HashMap<PublicKey, AreaOfInterest>>;
+
+// The slowest rate that avatars between the full rate and max radius are sent at.
+const MAX_REDUCED_INTERVAL: Duration = Duration::from_secs(2);
+// The interval that avatars just outside the full rate radius are sent at. This grows with
+// distance.
+const REDUCED_INTERVAL: Duration = Duration::from_millis(100);
+
+// Declared by each peer: how far away it wants to receive other avatars' poses from.
+#[derive(clap::Args, Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct AreaOfInterest {
+    /// Avatars within this distance are sent at the full update rate.
+    #[arg(long = "interest-full-rate-radius", default_value_t = 10.0)]
+    pub full_rate_radius: f32,
+    /// Avatars further away than this are not sent at all. Unlimited if not given.
+    #[arg(long = "interest-max-radius")]
+    pub max_radius: Option<f32>,
+}
+
+impl Default for AreaOfInterest {
+    fn default() -> Self {
+        Self {
+            full_rate_radius: 10.0,
+            max_radius: None,
+        }
+    }
+}
+
+impl AreaOfInterest {
+    // The minimum time between sending our pose to a peer at `distance`, or None if it
+    // shouldn't be sent at all.
+    pub fn send_interval(&self, distance: f32) -> Option<Duration> {
+        if self
+            .max_radius
+            .map_or(false, |max_radius| distance > max_radius)
+        {
+            return None;
+        }
+
+        if distance <= self.full_rate_radius {
+            return Some(Duration::ZERO);
+        }
+
+        Some(
+            REDUCED_INTERVAL
+                .mul_f32(distance / self.full_rate_radius.max(f32::EPSILON))
+                .min(MAX_REDUCED_INTERVAL),
+        )
+    }
+}
+
+// How often our pose should be sent to `node_id`, based on the distance between us and the
+// area of interest it declared.
+pub fn send_interval(
+    interests: &Interests,
+    avatar_poses: &AvatarPoses,
+    layer_poses: &LayerPoses,
+    node_id: &PublicKey,
+    pose: &AvatarPose,
+) -> Option<Duration> {
+    let interest = interests
+        .read(node_id, |_, interest| *interest)
+        .unwrap_or_default();
+
+    // Until we know where the peer is, send at the full rate.
+    let distance =
+        match avatars::composed_pose(avatar_poses, layer_poses, node_id, millis_since_start()) {
+            Some(their_pose) => their_pose.position.distance(pose.position),
+            None => return Some(Duration::ZERO),
+        };
+
+    interest.send_interval(distance)
+}
@@ -0,0 +1,157 @@
+use crate::roles::Role;
+use iroh_net::ticket::NodeTicket;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+pub type Token = [u8; 16];
+
+#[derive(Clone)]
+pub struct Invite {
+    pub room: String,
+    pub expires_at: SystemTime,
+    pub remaining_uses: u32,
+    pub role: Role,
+}
+
+impl Invite {
+    pub fn expired(&self) -> bool {
+        SystemTime::now() >= self.expires_at
+    }
+}
+
+// The options used when creating an invite in the UI.
+pub struct InviteOptions {
+    // The displayed room if empty.
+    pub room: String,
+    pub valid_minutes: u32,
+    pub uses: u32,
+    pub role: Role,
+}
+
+impl Default for InviteOptions {
+    fn default() -> Self {
+        Self {
+            room: String::new(),
+            valid_minutes: 60,
+            uses: 1,
+            role: Role::Editor,
+        }
+    }
+}
+
+// Invites we've handed out that can still be used.
+#[derive(Clone, Default)]
+pub struct Invites(Arc<scc::HashMap<Token, Invite>>);
+
+impl Invites {
+    pub fn create(&self, room: String, valid_for: Duration, uses: u32, role: Role) -> Token {
+        let token: Token = rand::random();
+
+        let _ = self.0.insert(
+            token,
+            Invite {
+                room,
+                expires_at: SystemTime::now() + valid_for,
+                remaining_uses: uses.max(1),
+                role,
+            },
+        );
+
+        token
+    }
+
+    // Use up one use of an invite to a room, returning the role it grants if it was valid.
+    pub async fn redeem(&self, token: &Token, room: &str) -> Option<Role> {
+        let mut entry = match self.0.get_async(token).await {
+            Some(entry) => entry,
+            None => return None,
+        };
+
+        if entry.get().room != room {
+            return None;
+        }
+
+        if entry.get().expired() {
+            entry.remove();
+            return None;
+        }
+
+        let invite = entry.get_mut();
+        invite.remaining_uses -= 1;
+        let role = invite.role;
+
+        if invite.remaining_uses == 0 {
+            entry.remove();
+        }
+
+        Some(role)
+    }
+
+    pub fn revoke(&self, token: &Token) {
+        self.0.remove(token);
+    }
+
+    // Unexpired invites, soonest to expire first.
+    pub fn list(&self) -> Vec<(Token, Invite)> {
+        self.0.retain(|_, invite| !invite.expired());
+
+        let mut invites = Vec::new();
+        self.0
+            .scan(|token, invite| invites.push((*token, invite.clone())));
+        invites.sort_by_key(|(_, invite)| invite.expires_at);
+        invites
+    }
+}
+
+// What to connect to, parsed from `<ticket>?room=<room>&invite=<token>`. Both parts after
+// the ticket are optional.
+pub struct JoinTicket {
+    pub ticket: NodeTicket,
+    pub room: Option<String>,
+    pub invite: Option<Token>,
+}
+
+pub fn format_ticket(ticket: &NodeTicket, room: &str, token: Option<&Token>) -> String {
+    let mut formatted = format!("{}?room={}", ticket, room);
+    if let Some(token) = token {
+        formatted.push_str("&invite=");
+        formatted.extend(token.iter().map(|byte| format!("{:02x}", byte)));
+    }
+    formatted
+}
+
+pub fn parse_ticket(input: &str) -> anyhow::Result<JoinTicket> {
+    let (ticket, query) = input.trim().split_once('?').unwrap_or((input.trim(), ""));
+
+    let mut join_ticket = JoinTicket {
+        ticket: NodeTicket::from_str(ticket)?,
+        room: None,
+        invite: None,
+    };
+
+    for pair in query.split('&').filter(|pair| !pair.is_empty()) {
+        match pair.split_once('=') {
+            Some(("room", room)) => join_ticket.room = Some(room.to_string()),
+            Some(("invite", token)) => join_ticket.invite = Some(parse_token(token)?),
+            _ => return Err(anyhow::anyhow!("Unexpected {:?} in ticket", pair)),
+        }
+    }
+
+    Ok(join_ticket)
+}
+
+fn parse_token(token: &str) -> anyhow::Result<Token> {
+    let bytes = (0..token.len())
+        .step_by(2)
+        .map(|index| {
+            token
+                .get(index..index + 2)
+                .and_then(|byte| u8::from_str_radix(byte, 16).ok())
+        })
+        .collect::<Option<Vec<u8>>>()
+        .ok_or_else(|| anyhow::anyhow!("Invalid invite token: {}", token))?;
+
+    Token::try_from(bytes.as_slice())
+        .map_err(|_| anyhow::anyhow!("Invalid invite token: {}", token))
+}
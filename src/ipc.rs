@@ -3,10 +3,23 @@ use tokio::sync::watch;
 
 pub struct PublicLayerState {
     pub layers: Vec<cpp::String>,
-    pub updated_layer: usize,
+    // The update index each layer was last changed in. Watchers can miss updates, so they
+    // compare these with the last update index they saw rather than looking at one layer.
+    pub layer_update_indices: Vec<u32>,
     pub update_index: u32,
 }
 
+impl PublicLayerState {
+    // The layers that changed after an update index.
+    pub fn updated_since(&self, update_index: u32) -> impl Iterator<Item = usize> + '_ {
+        self.layer_update_indices
+            .iter()
+            .enumerate()
+            .filter(move |(_, index)| **index > update_index)
+            .map(|(layer, _)| layer)
+    }
+}
+
 pub fn compare_and_send_existing_layer(
     sender: &mut watch::Sender<PublicLayerState>,
     serialized: cpp::String,
@@ -27,8 +40,41 @@ pub fn compare_and_send_existing_layer(
             layers.layers.push(serialized);
         }
 
-        layers.updated_layer = index;
         layers.update_index += 1;
+        // Padding layers are new too.
+        let update_index = layers.update_index;
+        layers
+            .layer_update_indices
+            .resize(layers.layers.len(), update_index);
+        layers.layer_update_indices[index] = update_index;
         true
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn updates_between_wakeups_are_kept() {
+        let (mut sender, mut receiver) = watch::channel(PublicLayerState {
+            layers: vec![cpp::String::new("#usda 1.0")],
+            layer_update_indices: vec![0],
+            update_index: 0,
+        });
+        let seen = receiver.borrow_and_update().update_index;
+
+        compare_and_send_existing_layer(&mut sender, cpp::String::new("#usda 1.0\n# 0"), 0);
+        compare_and_send_existing_layer(&mut sender, cpp::String::new("#usda 1.0\n# 2"), 2);
+        compare_and_send_existing_layer(&mut sender, cpp::String::new("#usda 1.0\n# 2"), 2);
+
+        let layers = receiver.borrow_and_update();
+        assert_eq!(layers.update_index, 2);
+        assert_eq!(layers.layer_update_indices, vec![1, 2, 2]);
+        assert_eq!(
+            layers.updated_since(seen).collect::<Vec<_>>(),
+            vec![0, 1, 2]
+        );
+        assert_eq!(layers.updated_since(1).collect::<Vec<_>>(), vec![1, 2]);
+    }
+}
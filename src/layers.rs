@@ -1,9 +1,23 @@
+use crate::acl::PathAcl;
+use crate::assets;
+use crate::util::avatar_name;
 use bbl_usd::{cpp, sdf, usd};
+use iroh_net::key::PublicKey;
+
+// The number of layers at the top of the root layer's sublayers that are never sent to peers.
+// Remote layers are inserted below these.
+pub const LOCAL_ONLY_LAYER_COUNT: usize = 2;
+
+// The first public sublayer only holds our avatar, so that it can be re-authored.
+pub const AVATAR_LAYER_INDEX: usize = 0;
 
 pub struct LocalLayers {
     root: sdf::LayerRefPtr,
     current_sublayer: sdf::LayerRefPtr,
+    avatar_layer: Option<sdf::LayerRefPtr>,
     private: sdf::LayerRefPtr,
+    // Smoothed poses of remote avatars.
+    remote_avatars: sdf::LayerRefPtr,
     sublayer_index: usize,
 }
 
@@ -20,17 +34,73 @@ impl LocalLayers {
 
         root.insert_sub_layer_path(private.get_identifier(), 0);
 
+        let remote_avatars = sdf::Layer::create_anonymous(".usda");
+
+        root.insert_sub_layer_path(remote_avatars.get_identifier(), 0);
+
         Self {
             root: local_root,
             current_sublayer,
+            avatar_layer: None,
             private,
+            remote_avatars,
             sublayer_index: 0,
         }
     }
 
-    pub fn set_private_edit_target(&mut self, stage: &usd::StageRefPtr) {
-        let edit_target = usd::EditTarget::new_from_layer_ref_ptr(&self.private);
-        stage.set_edit_target(&edit_target);
+    pub fn set_remote_avatars(&mut self, usda: &str) -> anyhow::Result<()> {
+        if !self.remote_avatars.import_from_str(&cpp::String::new(usda)) {
+            return Err(anyhow::anyhow!("Import of remote avatar poses failed."));
+        }
+
+        Ok(())
+    }
+
+    // Hide our own avatar from ourselves without touching what peers see.
+    pub fn hide_avatar(&mut self, node_id: PublicKey) -> anyhow::Result<()> {
+        let usda = format!(
+            r#"#usda 1.0
+
+over "avatars"
+{{
+    over "{}"
+    {{
+        token visibility = "invisible"
+    }}
+}}
+"#,
+            avatar_name(node_id)
+        );
+
+        if !self.private.import_from_str(&cpp::String::new(&usda)) {
+            return Err(anyhow::anyhow!("Import of the private layer failed."));
+        }
+
+        Ok(())
+    }
+
+    // Point our avatar's reference at a different asset. Returns the avatar layer to send.
+    pub fn set_avatar(&mut self, old: &str, new: &str) -> anyhow::Result<cpp::String> {
+        let layer = self
+            .avatar_layer
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("The avatar layer hasn't been created yet."))?;
+
+        let usda = layer
+            .export_to_string()
+            .map_err(|_| anyhow::anyhow!("Export of the avatar layer failed."))?;
+        let usda = assets::replace_asset_path(usda.as_str(), old, new);
+
+        if !layer.import_from_str(&cpp::String::new(&usda)) {
+            return Err(anyhow::anyhow!("Import of the new avatar failed."));
+        }
+
+        Ok(cpp::String::new(&usda))
+    }
+
+    // Where our public layers are in the root layer's sublayers.
+    pub fn root_identifier(&self) -> String {
+        self.root.get_identifier().to_string()
     }
 
     pub fn set_public_edit_target(&mut self, stage: &usd::StageRefPtr) {
@@ -38,13 +108,47 @@ impl LocalLayers {
         stage.set_edit_target(&edit_target);
     }
 
+    pub fn export_private(&self) -> anyhow::Result<cpp::String> {
+        self.private
+            .export_to_string()
+            .map_err(|_| anyhow::anyhow!("Export of the private layer failed."))
+    }
+
+    pub fn restore_private(&mut self, usda: &str) -> anyhow::Result<()> {
+        if !self.private.import_from_str(&cpp::String::new(usda)) {
+            return Err(anyhow::anyhow!("Import of the private layer failed."));
+        }
+
+        Ok(())
+    }
+
+    // Restore one of our public sublayers from a saved session. Sublayers must be restored in
+    // order, and the last one restored becomes the current sublayer.
+    pub fn restore_sublayer(&mut self, index: usize, usda: &str) -> anyhow::Result<()> {
+        while self.sublayer_index < index {
+            self.add_new_sublayer();
+        }
+
+        if !self
+            .current_sublayer
+            .import_from_str(&cpp::String::new(usda))
+        {
+            return Err(anyhow::anyhow!("Import of sublayer {} failed.", index));
+        }
+
+        Ok(())
+    }
+
     pub fn add_new_sublayer(&mut self) {
         let new_sublayer = sdf::Layer::create_anonymous(".usdc");
 
         self.root
             .insert_sub_layer_path(new_sublayer.get_identifier(), 0);
 
-        self.current_sublayer = new_sublayer;
+        let previous = std::mem::replace(&mut self.current_sublayer, new_sublayer);
+        if self.sublayer_index == AVATAR_LAYER_INDEX {
+            self.avatar_layer = Some(previous);
+        }
         self.sublayer_index += 1;
     }
 
@@ -56,12 +160,16 @@ impl LocalLayers {
     }
 }
 
+// Import a layer sent by `owner`, without the specs that its ACL doesn't allow. Returns the
+// paths of the stripped specs.
 pub fn update_remote_sublayers(
     root: &sdf::LayerRefPtr,
     sublayers: &mut Vec<sdf::LayerRefPtr>,
     index: usize,
-    string: &cpp::String,
-) -> anyhow::Result<()> {
+    string: &str,
+    owner: PublicKey,
+    acl: &PathAcl,
+) -> anyhow::Result<Vec<String>> {
     while index >= sublayers.len() {
         let sublayer = bbl_usd::sdf::Layer::create_anonymous(".usda");
         root.insert_sub_layer_path(sublayer.get_identifier(), 0);
@@ -71,9 +179,11 @@ pub fn update_remote_sublayers(
 
     let sublayer = &sublayers[index];
 
-    if !sublayer.import_from_str(string) {
-        return Err(anyhow::anyhow!("Import of {:?} failed.", string.as_str()));
+    let (filtered, stripped) = acl.filter_usda(owner, string);
+
+    if !sublayer.import_from_str(&cpp::String::new(&filtered)) {
+        return Err(anyhow::anyhow!("Import of {:?} failed.", filtered));
     }
 
-    Ok(())
+    Ok(stripped)
 }
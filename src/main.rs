@@ -7,20 +7,39 @@ use iroh_net::key::SecretKey;
 use std::path::PathBuf;
 use std::sync::Arc;
 
+mod acl;
+mod address_book;
+mod approval;
+mod assets;
+mod avatars;
+mod base_scene;
+mod chat;
+mod commit;
+mod export;
+mod interest;
+mod invite;
 mod ipc;
 mod layers;
 mod logging;
 mod networking;
+mod presence;
+mod presenter;
+mod relay;
+mod roles;
+mod rooms;
+mod session;
+mod signed_layer;
+mod trust;
 mod ui;
 mod util;
 
 use layers::LocalLayers;
 
-const ALPN: &[u8] = b"myalpn";
-
 struct UsdState {
     stage: usd::StageRefPtr,
     root_layer: sdf::LayerHandle,
+    // The identifier of the sublayer that holds our public layers.
+    local_root: String,
     pseudo_root: usd::Prim,
 }
 
@@ -32,6 +51,40 @@ struct Args {
     keyfile: Option<PathBuf>,
     #[arg(long)]
     peers_data: Option<PathBuf>,
+    #[arg(long)]
+    address_book: Option<PathBuf>,
+    /// The room to join and display. Peers can only connect to each other in the same room.
+    #[arg(long, default_value = "default")]
+    room: String,
+    /// Also serve a room without displaying it, as `<name>=<base>`. Can be given multiple
+    /// times.
+    #[arg(long = "host-room")]
+    host_rooms: Vec<rooms::HostedRoomArg>,
+    /// What to do when a peer's base scene differs from ours.
+    #[arg(long, value_enum, default_value_t = base_scene::MismatchPolicy::Warn)]
+    base_mismatch: base_scene::MismatchPolicy,
+    /// Directory to keep assets fetched from peers in. Defaults to a directory in the system
+    /// temporary directory.
+    #[arg(long)]
+    asset_cache: Option<PathBuf>,
+    /// Directory of a saved session to carry on from.
+    #[arg(long)]
+    resume: Option<PathBuf>,
+    /// Prim path prefix that peers may author under by default. Can be given multiple times.
+    /// Peers may author anywhere if none are given.
+    #[arg(long = "allowed-path")]
+    allowed_paths: Vec<String>,
+    /// Forward peers' layers to other peers, for peers that can't connect to each other.
+    #[arg(long)]
+    relay: bool,
+    #[command(flatten)]
+    avatar_updates: avatars::UpdatePolicy,
+    #[command(flatten)]
+    interest: interest::AreaOfInterest,
+    #[command(flatten)]
+    trust: trust::TrustRules,
+    #[command(flatten)]
+    approval: approval::HeadlessApproval,
 }
 
 #[tokio::main]
@@ -40,9 +93,37 @@ async fn main() -> anyhow::Result<()> {
 
     logging::setup()?;
 
+    rooms::validate_name(&args.room)?;
+    for (index, room) in args.host_rooms.iter().enumerate() {
+        if room.name == args.room
+            || args.host_rooms[..index]
+                .iter()
+                .any(|other| other.name == room.name)
+        {
+            anyhow::bail!("Room {:?} is given more than once", room.name);
+        }
+    }
+
     let approved_nodes = networking::ApprovedNodes::default();
-    let (approval_tx, mut approval_rx) = tokio::sync::mpsc::channel(10);
+    let (approval_tx, approval_rx) = tokio::sync::mpsc::unbounded_channel();
     let connected_nodes = networking::ConnectedNodes::default();
+    let address_book = address_book::AddressBook::load(args.address_book.take())?;
+    let pending_approvals = approval::PendingApprovals::default();
+
+    tokio::spawn(approval::handle_requests(
+        args.approval.clone(),
+        approval_rx,
+        pending_approvals.clone(),
+        address_book.clone(),
+    ));
+
+    if args.approval.prompt {
+        approval::spawn_prompt(pending_approvals.clone(), address_book.clone());
+    }
+
+    if let Some(path) = args.approval.control_socket.clone() {
+        approval::spawn_control_socket(path, pending_approvals.clone(), address_book.clone())?;
+    }
 
     let secret_key = match args.keyfile {
         Some(keyfile) => SecretKey::try_from_openssh(std::fs::read(keyfile)?)?,
@@ -51,7 +132,12 @@ async fn main() -> anyhow::Result<()> {
 
     let mut endpoint_builder = iroh_net::MagicEndpoint::builder()
         .secret_key(secret_key)
-        .alpns(vec![ALPN.to_owned()]);
+        .alpns(
+            std::iter::once(&args.room)
+                .chain(args.host_rooms.iter().map(|room| &room.name))
+                .map(|room| rooms::alpn(room))
+                .collect(),
+        );
 
     if let Some(peers_data_path) = args.peers_data.take() {
         endpoint_builder = endpoint_builder.peers_data_path(peers_data_path);
@@ -95,12 +181,8 @@ async fn main() -> anyhow::Result<()> {
 
     local_layers.set_public_edit_target(&stage);
 
-    // note: prefix with _avatar as names can't start with numbers.
     let avatar = stage
-        .define_prim(
-            &format!("/avatars/avatar_{}", endpoint.node_id().fmt_short())[..],
-            "Xform",
-        )
+        .define_prim(&util::avatar_path(endpoint.node_id())[..], "Xform")
         .map_err(|err| anyhow::anyhow!("{:?}", err))?;
 
     let xformable = usd::Xformable::new(&avatar);
@@ -113,29 +195,43 @@ async fn main() -> anyhow::Result<()> {
     let mut references = avatar.get_references();
     references.add_reference(&cpp::String::new(&args.avatar));
 
-    local_layers.set_private_edit_target(&stage);
+    local_layers.hide_avatar(endpoint.node_id())?;
 
-    {
-        xformable
-            .add_xform_op(bbl_usd::ffi::usdGeom_XformOpType_usdGeom_XformOpType_TypeScale)
-            .set(
-                &vt::Value::from_dvec3(glam::DVec3::ZERO),
-                Default::default(),
-            );
-    }
+    // The asset our avatar's reference currently points at.
+    let mut current_avatar = args.avatar.clone();
 
     let (mut state_tx, state_rx) = tokio::sync::watch::channel(ipc::PublicLayerState {
         layers: vec![local_layers.export().1],
-        updated_layer: 0,
+        layer_update_indices: vec![0],
         update_index: 0,
     });
 
     local_layers.add_new_sublayer();
 
+    let saved_session = args.resume.as_deref().map(session::load).transpose()?;
+
+    if let Some(saved_session) = saved_session.as_ref() {
+        // Our avatar is authored again, so its layer isn't restored.
+        for (index, usda) in &saved_session.public_layers {
+            if *index == layers::AVATAR_LAYER_INDEX {
+                continue;
+            }
+            local_layers.restore_sublayer(*index, usda)?;
+            let (index, serialized) = local_layers.export();
+            ipc::compare_and_send_existing_layer(&mut state_tx, serialized, index);
+        }
+
+        // Only hides the avatar of the node that saved it.
+        if saved_session.node_id == endpoint.node_id() {
+            local_layers.restore_private(&saved_session.private_layer)?;
+        }
+    }
+
     local_layers.set_public_edit_target(&stage);
 
     let usd_state = Arc::new(tokio::sync::RwLock::new(UsdState {
         root_layer,
+        local_root: local_layers.root_identifier(),
         pseudo_root: prim,
         stage,
     }));
@@ -163,34 +259,131 @@ async fn main() -> anyhow::Result<()> {
     let mut grab_toggled = false;
     let mut prev_cursor_pos = glam::DVec2::from(glfw_backend.window.get_cursor_pos());
 
-    let proj = glam::DMat4::perspective_rh_gl(59.0_f64.to_radians(), 1.0, 0.01, 1000.0);
+    let mut fov_degrees = 59.0_f32;
 
     let addr = endpoint.my_addr().await?;
 
+    let (pose_tx, pose_rx) = tokio::sync::watch::channel(avatars::PoseSample::default());
+
+    let (presenter_tx, presenter_rx) = tokio::sync::watch::channel(None);
+
+    let (interest_tx, interest_rx) = tokio::sync::watch::channel(args.interest);
+
+    let (presence_tx, presence_rx) = tokio::sync::watch::channel(presence::Presence::new(
+        endpoint.node_id(),
+        args.avatar.clone(),
+    ));
+
+    let asset_cache = assets::AssetCache::new(
+        args.asset_cache
+            .take()
+            .unwrap_or_else(|| std::env::temp_dir().join("usd-render-assets")),
+    )?;
+
+    let (asset_manifest_tx, asset_manifest_rx) = tokio::sync::watch::channel(Default::default());
+    // Empty until the layers have been rewritten for the first time.
+    let (session_layers_tx, session_layers_rx) =
+        tokio::sync::watch::channel(ipc::PublicLayerState {
+            layers: Vec::new(),
+            layer_update_indices: Vec::new(),
+            update_index: 0,
+        });
+
+    tokio::spawn(assets::publish_session_layers(
+        asset_cache.clone(),
+        state_rx.clone(),
+        session_layers_tx,
+        asset_manifest_tx,
+    ));
+
     let networking_state = networking::State {
         endpoint: endpoint.clone(),
+        room: args.room.clone(),
         approved_nodes: approved_nodes.clone(),
         approval_queue: approval_tx.clone(),
         connected_nodes: connected_nodes.clone(),
         state: state_rx.clone(),
+        session_layers: session_layers_rx,
         usd: usd_state.clone(),
+        address_book: address_book.clone(),
+        presence: presence_rx,
+        participants: Default::default(),
+        chat: Default::default(),
+        pose: pose_rx,
+        avatar_poses: Default::default(),
+        layer_poses: Default::default(),
+        unresolved_avatars: Default::default(),
+        presenter: presenter_rx,
+        presenters: Default::default(),
+        bandwidth: Default::default(),
+        interest: interest_rx,
+        interests: Default::default(),
+        acls: Default::default(),
+        default_acl: acl::PathAcl {
+            allowed_prefixes: (!args.allowed_paths.is_empty()).then(|| args.allowed_paths.clone()),
+        },
+        connections: Default::default(),
+        granted_roles: Default::default(),
+        admin_commands: tokio::sync::broadcast::channel(16).0,
+        remote_authors: Default::default(),
+        relay: args.relay,
+        relayed_layers: tokio::sync::broadcast::channel(100).0,
+        trust: trust::Trust::new(args.trust.clone()),
+        approval_requests: Default::default(),
+        invites: Default::default(),
+        base_scene: Arc::new(base_scene::BaseScene::hash(&args.base)?),
+        base_mismatch: args.base_mismatch,
+        assets: asset_cache,
+        asset_manifest: asset_manifest_rx,
+        remote_assets: Default::default(),
+        pending_reloads: Default::default(),
     };
 
+    if let Some(saved_session) = saved_session {
+        for layer in &saved_session.remote_layers {
+            if let Err(error) = networking::restore_layer(&networking_state, layer).await {
+                log::error!(
+                    "Restoring a layer from {} failed: {}",
+                    layer.author.fmt_short(),
+                    error
+                );
+            }
+        }
+    }
+
+    let hosted_rooms: Vec<rooms::HostedRoom> = args
+        .host_rooms
+        .drain(..)
+        .map(|room| rooms::HostedRoom::new(room, &networking_state))
+        .collect::<anyhow::Result<_>>()?;
+
+    let rooms: rooms::Rooms = Arc::new(
+        std::iter::once(&networking_state)
+            .chain(hosted_rooms.iter().map(|room| &room.state))
+            .map(|state| (state.room.clone(), state.clone()))
+            .collect(),
+    );
+
     tokio::spawn({
-        let networking_state = networking_state.clone();
+        let rooms = rooms.clone();
+        let endpoint = endpoint.clone();
         async move {
-            while let Some(connecting) = networking_state.endpoint.accept().await {
-                tokio::spawn(networking::accept(connecting, networking_state.clone()));
+            while let Some(connecting) = endpoint.accept().await {
+                tokio::spawn(networking::accept(connecting, rooms.clone()));
             }
         }
     });
 
     let ticket = iroh_net::ticket::NodeTicket::new(addr.clone())?;
 
-    println!("{}", ticket);
+    for room in rooms.keys() {
+        println!("{}", invite::format_ticket(&ticket, room, None));
+    }
 
     let mut ui_state = ui::State::default();
 
+    let mut avatar_updater = avatars::AvatarUpdater::new(args.avatar_updates.clone());
+
     while !glfw_backend.window.should_close() {
         glfw_backend.glfw.poll_events();
         glfw_backend.tick();
@@ -204,6 +397,14 @@ async fn main() -> anyhow::Result<()> {
                     glfw::WindowEvent::Key(Key::Escape, _, Action::Press, _) => {
                         glfw_backend.window.set_should_close(true)
                     }
+                    glfw::WindowEvent::Key(Key::F, _, Action::Press, _) => {
+                        if let Some(presenter) = ui_state.following_presenter.take() {
+                            log::info!(
+                                "Stopped following {}'s presentation",
+                                address_book.name(&presenter)
+                            );
+                        }
+                    }
                     glfw::WindowEvent::Key(Key::G, _, Action::Press, _) => {
                         grab_toggled = !grab_toggled;
                         if grab_toggled {
@@ -235,13 +436,22 @@ async fn main() -> anyhow::Result<()> {
 
         // Camera movement and rotation
 
-        let movement = if egui.wants_keyboard_input() {
+        let following_presenter = ui_state.following_presenter.is_some();
+
+        let movement = if egui.wants_keyboard_input() || following_presenter {
             glam::IVec3::ZERO
         } else {
             util::get_movement(&glfw_backend.window)
+        };
+
+        // Any local movement stops following a peer.
+        if movement != glam::IVec3::ZERO
+            || (grab_toggled && !following_presenter && delta != glam::DVec2::ZERO)
+        {
+            ui_state.following = None;
         }
-        .as_vec3()
-            * 0.1;
+
+        let movement = movement.as_vec3() * 0.1;
 
         let movement = movement.x * camera.final_transform.right()
             + movement.y * camera.final_transform.forward()
@@ -249,7 +459,7 @@ async fn main() -> anyhow::Result<()> {
 
         camera.driver_mut::<Position>().translate(movement);
 
-        if grab_toggled {
+        if grab_toggled && !following_presenter {
             let yaw_pitch = -delta * 0.25;
             camera
                 .driver_mut::<YawPitch>()
@@ -259,67 +469,275 @@ async fn main() -> anyhow::Result<()> {
         // Egui
 
         {
-            while !ui_state.approval_queue.is_full() {
-                if let Ok(request) = approval_rx.try_recv() {
-                    ui_state.approval_queue.push((
-                        request.node_id,
-                        request.direction,
-                        Some(request.response_sender),
-                    ));
-                } else {
-                    break;
-                }
-            }
-
             let connection_infos = endpoint.connection_infos().await?;
             let log_lines = logging::get_lines().await;
 
             egui::Window::new("Network").show(&egui, |ui| {
-                ui::draw_node_info(ui, &addr, &ticket, &mut glfw_backend.window);
+                ui::draw_node_info(
+                    ui,
+                    &addr,
+                    &ticket,
+                    &networking_state.room,
+                    &mut glfw_backend.window,
+                );
+
+                ui.collapsing("Invites", |ui| {
+                    ui::draw_invites(
+                        ui,
+                        &networking_state,
+                        &rooms,
+                        &ticket,
+                        &mut ui_state,
+                        &mut glfw_backend.window,
+                    );
+                });
 
                 ui::draw_connect_to_node(ui, &networking_state, &addr, &mut ui_state);
 
                 ui.collapsing("Connections", |ui| {
-                    ui::draw_connection_grid(ui, &connection_infos);
+                    ui::draw_connection_grid(ui, &connection_infos, &address_book, &mut ui_state);
                 });
 
-                if !ui_state.approval_queue.is_empty() {
-                    ui::draw_approval_queue(ui, &mut ui_state, &approved_nodes);
-                }
+                ui.collapsing("Address Book", |ui| {
+                    ui::draw_address_book(ui, &networking_state, &mut ui_state);
+                });
+
+                ui::draw_approval_queue(
+                    ui,
+                    &mut ui_state,
+                    &pending_approvals,
+                    &rooms,
+                    &address_book,
+                );
+
+                ui.collapsing("Bandwidth", |ui| {
+                    ui::draw_bandwidth(ui, &networking_state, &mut ui_state);
+                });
 
                 ui.collapsing("Log", |ui| {
                     log_lines.draw(ui);
                 });
 
-                ui::draw_buttons(ui, &networking_state);
+                ui.collapsing("Export", |ui| {
+                    ui::draw_export(ui, &networking_state, &args.base, &mut ui_state);
+                });
+
+                ui.collapsing("Commit to base", |ui| {
+                    ui::draw_commit(ui, &networking_state, &args.base, &mut ui_state);
+                });
+
+                ui::draw_buttons(ui, &networking_state, &mut ui_state);
+            });
+
+            egui::Window::new("Participants").show(&egui, |ui| {
+                ui::draw_participants(ui, &presence_tx, &networking_state, &mut ui_state);
+                ui.collapsing("Area of interest", |ui| {
+                    ui::draw_area_of_interest(ui, &interest_tx);
+                });
+                ui.collapsing("Permissions", |ui| {
+                    ui::draw_permissions(ui, &networking_state, &mut ui_state);
+                });
+            });
+
+            if let Some(presenter) = presenter::yield_presentation(
+                &networking_state.presenters,
+                endpoint.node_id(),
+                &presenter_tx,
+            ) {
+                log::warn!(
+                    "Stopped presenting, as {} is presenting too",
+                    address_book.name(&presenter)
+                );
+                ui_state.yielded_presentation_to = Some(presenter);
+            }
+
+            egui::Window::new("Presenter").show(&egui, |ui| {
+                ui::draw_presenter(
+                    ui,
+                    &presenter_tx,
+                    &networking_state,
+                    &mut ui_state,
+                    &mut fov_degrees,
+                );
+            });
+
+            ui::draw_presenter_indicator(&egui, &presenter_tx, &networking_state, &ui_state);
+
+            egui::Window::new("Chat").show(&egui, |ui| {
+                ui::draw_chat(ui, &networking_state, &mut ui_state);
             });
         }
 
         let output = egui.end_frame();
         let meshes = egui.tessellate(output.shapes, output.pixels_per_point);
 
+        // Our layers are copied here and written out in the background, so the frame
+        // doesn't wait on the disk.
+        let save_session_to = ui_state.save_session_to.lock().unwrap().take();
+        if let Some(directory) = save_session_to {
+            let public_layers: Vec<String> = state_tx
+                .borrow()
+                .layers
+                .iter()
+                .map(|layer| layer.as_str().to_string())
+                .collect();
+            match local_layers.export_private() {
+                Ok(private_layer) => {
+                    let private_layer = private_layer.as_str().to_string();
+                    let node_id = endpoint.node_id();
+                    let remote_authors = networking_state.remote_authors.clone();
+                    util::spawn_fallible(
+                        async move {
+                            session::save(
+                                directory,
+                                node_id,
+                                public_layers,
+                                private_layer,
+                                &remote_authors,
+                            )
+                            .await
+                        },
+                        |error| async move {
+                            log::error!("Saving the session failed: {}", error);
+                        },
+                    );
+                }
+                Err(error) => log::error!("Saving the session failed: {}", error),
+            }
+        }
+
         // Update usd camera state
 
+        let mut current_fov_degrees = fov_degrees;
+        let now = util::millis_since_start();
+
+        if let Some(presenter) = ui_state.following_presenter {
+            let pose = avatars::composed_pose(
+                &networking_state.avatar_poses,
+                &networking_state.layer_poses,
+                &presenter,
+                now,
+            );
+            let presenter_state = networking_state
+                .presenters
+                .read(&presenter, |_, presenter_state| *presenter_state);
+
+            match (pose, presenter_state) {
+                (Some(pose), Some(presenter_state)) => {
+                    camera.driver_mut::<Position>().position = pose.position;
+                    camera
+                        .driver_mut::<YawPitch>()
+                        .set_rotation_quat(pose.rotation);
+                    if let Some(presenter_fov_degrees) = presenter_state.fov_degrees {
+                        current_fov_degrees = presenter_fov_degrees;
+                    }
+                }
+                _ => {
+                    log::info!(
+                        "Stopped following {}: no longer presenting",
+                        address_book.name(&presenter)
+                    );
+                    ui_state.following_presenter = None;
+                }
+            }
+        } else if let Some(node_id) = ui_state.following {
+            // Follows the avatar's transform as it's shown, whether or not poses are
+            // streaming.
+            match avatars::composed_pose(
+                &networking_state.avatar_poses,
+                &networking_state.layer_poses,
+                &node_id,
+                now,
+            )
+            .filter(|_| networking_state.participants.contains(&node_id))
+            {
+                Some(pose) => {
+                    // The rig's smoothing eases the camera towards the pose.
+                    camera.driver_mut::<Position>().position = pose.position;
+                    camera
+                        .driver_mut::<YawPitch>()
+                        .set_rotation_quat(pose.rotation);
+                }
+                None => {
+                    log::info!(
+                        "Stopped following {}: no longer connected",
+                        address_book.name(&node_id)
+                    );
+                    ui_state.following = None;
+                }
+            }
+        }
+
         let transform = camera.update(1.0 / 60.0);
 
+        let pose = avatars::AvatarPose::from_transform(transform);
+
+        let avatar_update = avatar_updater.update(pose, now, connected_nodes.len());
+
+        if avatar_update.send_pose {
+            pose_tx.send_replace(avatars::PoseSample::new(pose));
+        }
+
+        let proj = glam::DMat4::perspective_rh_gl(
+            (current_fov_degrees as f64).to_radians(),
+            1.0,
+            0.01,
+            1000.0,
+        );
+
         engine.set_camera_state(util::view_from_camera_transform(transform), proj);
 
         let usd_state = usd_state.write().await;
 
-        position_xform_op.set(
-            &vt::Value::from_dvec3(transform.position.as_dvec3()),
-            Default::default(),
-        );
-        rotation_xform_op.set(
-            &vt::Value::from_dquat(
-                transform.rotation.as_f64() * glam::DQuat::from_rotation_y(180_f64.to_radians()),
-            ),
-            Default::default(),
-        );
+        if let Some(pose) = avatar_update.author_layer {
+            position_xform_op.set(
+                &vt::Value::from_dvec3(pose.position.as_dvec3()),
+                Default::default(),
+            );
+            rotation_xform_op.set(
+                &vt::Value::from_dquat(pose.avatar_rotation()),
+                Default::default(),
+            );
+        }
+
+        if let Err(error) = local_layers.set_remote_avatars(&avatars::remote_avatars_usda(
+            &networking_state.avatar_poses,
+            &networking_state.unresolved_avatars,
+            &networking_state.participants,
+        )) {
+            log::error!("{}", error);
+        }
+
+        let new_avatar =
+            Some(presence_tx.borrow().avatar.clone()).filter(|avatar| *avatar != current_avatar);
+
+        let avatar_layer = new_avatar.and_then(|new_avatar| {
+            match local_layers.set_avatar(&current_avatar, &new_avatar) {
+                Ok(avatar_layer) => {
+                    current_avatar = new_avatar;
+                    Some(avatar_layer)
+                }
+                Err(error) => {
+                    log::error!("Changing avatar failed: {}", error);
+                    // Peers are told about the avatar that's actually in the layer, and the
+                    // change isn't retried every frame.
+                    presence_tx.send_modify(|presence| presence.avatar = current_avatar.clone());
+                    None
+                }
+            }
+        });
 
         let usd_state = usd_state.downgrade();
 
-        {
+        // Only one layer is sent per update, so the avatar layer goes on its own and the
+        // current sublayer follows next frame.
+        if let Some(serialized) = avatar_layer {
+            ipc::compare_and_send_existing_layer(
+                &mut state_tx,
+                serialized,
+                layers::AVATAR_LAYER_INDEX,
+            );
+        } else {
             let (index, serialized) = local_layers.export();
             ipc::compare_and_send_existing_layer(&mut state_tx, serialized, index);
         }
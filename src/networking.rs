@@ -1,18 +1,53 @@
-use crate::{ipc, layers, util::spawn_fallible, UsdState, ALPN};
+use crate::{
+    acl::{Acls, PathAcl},
+    address_book::AddressBook,
+    assets::{AssetCache, AssetHash, AssetManifest, AssetRef, RemoteAssets},
+    avatars::{self, AvatarPoses, LayerPoses, PoseSample, UnresolvedAvatars},
+    base_scene::{BaseScene, MismatchPolicy},
+    chat::{Chat, ChatMessage},
+    interest::{self, AreaOfInterest, Interests},
+    invite::{self, Invites},
+    ipc, layers,
+    presence::{Participants, Presence},
+    presenter::{PresenterState, Presenters},
+    relay::{self, AuthorLayers, RelayedLayer, RemoteAuthor, RemoteAuthors},
+    roles::{AdminCommand, GrantedRoles, Role},
+    rooms::{self, Rooms},
+    signed_layer::{self, SignedLayer},
+    trust::Trust,
+    util::spawn_fallible,
+    UsdState,
+};
 use bbl_usd::cpp;
 use iroh_net::{key::PublicKey, magic_endpoint::accept_conn, AddrInfo, MagicEndpoint, NodeAddr};
 use std::collections::HashSet;
 use std::sync::{atomic, Arc};
-use tokio::sync::{mpsc, oneshot, watch};
+use tokio::io::AsyncReadExt;
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
 
-pub type ApprovedNodes = Arc<scc::HashMap<PublicKey, NodeSharingPolicy>>;
+pub type ApprovedNodes = Arc<scc::HashMap<PublicKey, Approval>>;
 pub type ConnectedNodes = Arc<scc::HashSet<PublicKey>>;
-pub type ApprovalQueue = tokio::sync::mpsc::Sender<NodeApprovalRequest>;
+// Open connections, so that nodes can be kicked.
+pub type Connections = Arc<scc::HashMap<PublicKey, quinn::Connection>>;
+pub type ApprovalQueue = tokio::sync::mpsc::UnboundedSender<NodeApprovalRequest>;
+// When each node recently asked for approval.
+pub type ApprovalRequests = Arc<scc::HashMap<PublicKey, Vec<tokio::time::Instant>>>;
+
+// How long to wait for a connecting node's handshake.
+const HANDSHAKE_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(10);
+// How long a request can wait for approval before the connection is closed.
+const APPROVAL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(120);
+// How long to wait for other assets to be fetched before reloading an author's layers.
+const RELOAD_DELAY: std::time::Duration = std::time::Duration::from_millis(500);
+// Nodes asking for approval more often than this are refused without asking.
+const MAX_APPROVAL_REQUESTS: usize = 3;
+const APPROVAL_REQUEST_WINDOW: std::time::Duration = std::time::Duration::from_secs(60);
 
 // Adds a nodeid to the connected nodes set on creation, removes it on drop.
 pub struct NodeConnection {
     connected_nodes: ConnectedNodes,
     node_id: PublicKey,
+    name: String,
 }
 
 impl NodeConnection {
@@ -21,9 +56,13 @@ impl NodeConnection {
             Some(Self {
                 connected_nodes: state.connected_nodes.clone(),
                 node_id,
+                name: state.address_book.name(&node_id),
             })
         } else {
-            log::info!("Not connecting to {}: already connected", node_id);
+            log::info!(
+                "Not connecting to {}: already connected",
+                state.address_book.name(&node_id)
+            );
             None
         }
     }
@@ -31,7 +70,7 @@ impl NodeConnection {
 
 impl Drop for NodeConnection {
     fn drop(&mut self) {
-        log::info!("Disconnecting from {}", self.node_id);
+        log::info!("Disconnecting from {}", self.name);
         tokio::spawn({
             let connected_nodes = self.connected_nodes.clone();
             let node_id = self.node_id;
@@ -42,8 +81,22 @@ impl Drop for NodeConnection {
     }
 }
 
+// Sent by the connecting node on a stream of its own, before anything else.
+#[derive(Default, serde::Serialize, serde::Deserialize)]
+pub struct Handshake {
+    pub invite: Option<invite::Token>,
+    pub base_scene: BaseScene,
+}
+
+// Sent back on the handshake stream by the accepting node.
+#[derive(serde::Serialize, serde::Deserialize)]
+pub struct HandshakeReply {
+    pub base_scene: BaseScene,
+}
+
 pub struct NodeApprovalRequest {
     pub node_id: PublicKey,
+    pub room: String,
     pub direction: NodeApprovalDirection,
     pub response_sender: oneshot::Sender<NodeApprovalResponse>,
 }
@@ -54,10 +107,16 @@ pub enum NodeApprovalDirection {
 }
 
 pub enum NodeApprovalResponse {
-    Approved(NodeSharingPolicy),
+    Approved(Approval),
     Denied,
 }
 
+#[derive(Clone)]
+pub struct Approval {
+    pub sharing: NodeSharingPolicy,
+    pub role: Role,
+}
+
 // Who should a node's addrinfo be shared with?
 #[derive(Clone)]
 pub enum NodeSharingPolicy {
@@ -74,9 +133,19 @@ impl NodeSharingPolicy {
     }
 }
 
+#[derive(Clone, Copy)]
 enum PacketType {
     Data = 0,
     NewNode = 1,
+    Presence = 2,
+    Chat = 3,
+    Pose = 4,
+    Presenter = 5,
+    Interest = 6,
+    Role = 7,
+    Admin = 8,
+    RelayedData = 9,
+    Assets = 10,
 }
 
 impl PacketType {
@@ -84,23 +153,122 @@ impl PacketType {
         Some(match byte {
             0 => Self::Data,
             1 => Self::NewNode,
+            2 => Self::Presence,
+            3 => Self::Chat,
+            4 => Self::Pose,
+            5 => Self::Presenter,
+            6 => Self::Interest,
+            7 => Self::Role,
+            8 => Self::Admin,
+            9 => Self::RelayedData,
+            10 => Self::Assets,
             _ => return None,
         })
     }
+
+    const ALL: [Self; 11] = [
+        Self::Data,
+        Self::NewNode,
+        Self::Presence,
+        Self::Chat,
+        Self::Pose,
+        Self::Presenter,
+        Self::Interest,
+        Self::Role,
+        Self::Admin,
+        Self::RelayedData,
+        Self::Assets,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Data => "Layers",
+            Self::NewNode => "New nodes",
+            Self::Presence => "Presence",
+            Self::Chat => "Chat",
+            Self::Pose => "Poses",
+            Self::Presenter => "Presenter",
+            Self::Interest => "Interest",
+            Self::Role => "Roles",
+            Self::Admin => "Admin",
+            Self::RelayedData => "Relayed layers",
+            Self::Assets => "Assets",
+        }
+    }
+}
+
+// Total bytes written to streams per packet type.
+#[derive(Default)]
+pub struct Bandwidth {
+    sent: [atomic::AtomicU64; PacketType::ALL.len()],
+}
+
+impl Bandwidth {
+    fn record(&self, packet_type: PacketType, bytes: usize) {
+        self.sent[packet_type as usize].fetch_add(bytes as u64, atomic::Ordering::Relaxed);
+    }
+
+    pub fn totals(&self) -> Vec<(&'static str, u64)> {
+        PacketType::ALL
+            .iter()
+            .map(|packet_type| {
+                (
+                    packet_type.name(),
+                    self.sent[*packet_type as usize].load(atomic::Ordering::Relaxed),
+                )
+            })
+            .collect()
+    }
 }
 
 #[derive(Clone)]
 pub struct State {
+    pub room: String,
     pub endpoint: MagicEndpoint,
     pub approved_nodes: ApprovedNodes,
     pub approval_queue: ApprovalQueue,
     pub connected_nodes: ConnectedNodes,
     pub state: watch::Receiver<ipc::PublicLayerState>,
+    // Our public layers with local asset paths rewritten to session urls, for sending.
+    pub session_layers: watch::Receiver<ipc::PublicLayerState>,
     pub usd: Arc<tokio::sync::RwLock<UsdState>>,
+    pub address_book: AddressBook,
+    pub presence: watch::Receiver<Presence>,
+    pub participants: Participants,
+    pub chat: Chat,
+    pub pose: watch::Receiver<PoseSample>,
+    pub avatar_poses: AvatarPoses,
+    pub layer_poses: LayerPoses,
+    pub unresolved_avatars: UnresolvedAvatars,
+    pub presenter: watch::Receiver<Option<PresenterState>>,
+    pub presenters: Presenters,
+    pub bandwidth: Arc<Bandwidth>,
+    pub interest: watch::Receiver<AreaOfInterest>,
+    pub interests: Interests,
+    pub acls: Acls,
+    // Used for peers without an entry in `acls`.
+    pub default_acl: PathAcl,
+    pub connections: Connections,
+    pub granted_roles: GrantedRoles,
+    pub admin_commands: broadcast::Sender<AdminCommand>,
+    pub remote_authors: RemoteAuthors,
+    // Whether to forward layers to nodes that may not be connected to their author.
+    pub relay: bool,
+    pub relayed_layers: broadcast::Sender<RelayedLayer>,
+    pub trust: Trust,
+    pub approval_requests: ApprovalRequests,
+    pub invites: Invites,
+    pub base_scene: Arc<BaseScene>,
+    pub base_mismatch: MismatchPolicy,
+    pub assets: AssetCache,
+    pub asset_manifest: watch::Receiver<AssetManifest>,
+    pub remote_assets: RemoteAssets,
+    // Authors whose layers are about to be reloaded for assets that were just fetched.
+    pub pending_reloads: Arc<scc::HashSet<PublicKey>>,
 }
 
-pub async fn accept(connecting: quinn::Connecting, state: State) {
-    let (node_id, _alpn, connection) = match accept_conn(connecting).await {
+pub async fn accept(connecting: quinn::Connecting, rooms: Rooms) {
+    let (node_id, alpn, connection) = match accept_conn(connecting).await {
         Ok(data) => data,
         Err(error) => {
             log::error!("Error accepting incoming connection: {}", error);
@@ -108,7 +276,24 @@ pub async fn accept(connecting: quinn::Connecting, state: State) {
         }
     };
 
-    log::info!("Accepted connection from {}", node_id);
+    let state = match rooms::room_from_alpn(&alpn).and_then(|room| rooms.get(room)) {
+        Some(state) => state.clone(),
+        None => {
+            log::warn!(
+                "{} asked for an unknown room: {}",
+                node_id.fmt_short(),
+                alpn
+            );
+            connection.close(0_u32.into(), b"unknown room");
+            return;
+        }
+    };
+
+    log::info!(
+        "Accepted connection from {} to room {}",
+        state.address_book.name(&node_id),
+        state.room
+    );
 
     let _node_connection = match NodeConnection::new(&state, node_id).await {
         Some(node_connection) => node_connection,
@@ -118,6 +303,35 @@ pub async fn accept(connecting: quinn::Connecting, state: State) {
         }
     };
 
+    let name = state.address_book.name(&node_id);
+
+    let handshake = match receive_handshake(&state, &connection).await {
+        Ok(handshake) => handshake,
+        Err(error) => {
+            log::error!("No handshake from {}: {}", name, error);
+            connection.close(0_u32.into(), b"no handshake");
+            return;
+        }
+    };
+
+    if !check_base_scene(&state, node_id, &handshake.base_scene) {
+        connection.close(0_u32.into(), b"base scene mismatch");
+        return;
+    }
+
+    if let Some(token) = handshake.invite {
+        if !state.approved_nodes.contains_async(&node_id).await {
+            match state.invites.redeem(&token, &state.room).await {
+                Some(role) => {
+                    log::info!("{} joined with an invite as {}", name, role.as_str());
+                    approve(&state, node_id, role).await;
+                }
+                // Ask as if they had no invite.
+                None => log::warn!("{} used an invalid or expired invite", name),
+            }
+        }
+    }
+
     if !wait_for_approval(
         state.clone(),
         node_id,
@@ -138,64 +352,196 @@ async fn wait_for_approval(
     direction: NodeApprovalDirection,
     connection: Option<quinn::Connection>,
 ) -> bool {
-    if state.approved_nodes.contains_async(&node_id).await {
+    if state.approved_nodes.contains_async(&node_id).await || auto_approve(&state, node_id).await {
         return true;
     }
 
-    log::info!("Waiting for approval to connect to {}", node_id);
+    let name = state.address_book.name(&node_id);
 
-    let send_and_get_response = || async move {
-        let (tx, rx) = oneshot::channel();
+    let close = |reason: &[u8]| {
+        if let Some(connection) = connection.as_ref() {
+            connection.close(0_u32.into(), reason);
+        }
+    };
 
-        state
-            .approval_queue
-            .send(NodeApprovalRequest {
-                node_id,
-                direction,
-                response_sender: tx,
-            })
-            .await?;
+    if too_many_approval_requests(&state, node_id).await {
+        log::warn!("Refused {}: too many approval requests", name);
+        close(b"too many approval requests");
+        return false;
+    }
 
-        log::debug!("Sent message on approval queue");
+    log::info!("Waiting for approval to connect to {}", name);
 
-        let response = rx.await?;
+    let (tx, rx) = oneshot::channel();
 
-        log::info!("Got response");
+    if let Err(error) = state.approval_queue.send(NodeApprovalRequest {
+        node_id,
+        room: state.room.clone(),
+        direction,
+        response_sender: tx,
+    }) {
+        log::error!("{}", error);
+        close(b"error");
+        return false;
+    }
 
-        Ok::<_, anyhow::Error>(response)
-    };
+    log::debug!("Sent message on approval queue");
 
-    let response = match send_and_get_response().await {
-        Ok(response) => response,
-        Err(error) => {
-            if let Some(connection) = connection {
-                connection.close(0_u32.into(), b"error");
-            }
+    let approval = match tokio::time::timeout(APPROVAL_TIMEOUT, rx).await {
+        Ok(Ok(NodeApprovalResponse::Approved(approval))) => approval,
+        Ok(Ok(NodeApprovalResponse::Denied)) => {
+            close(b"denied");
+            log::info!("Denied connection to {}", name);
+            return false;
+        }
+        Ok(Err(error)) => {
+            close(b"error");
             log::error!("{}", error);
             return false;
         }
-    };
-
-    let node_sharing = match response {
-        NodeApprovalResponse::Approved(node_sharing) => node_sharing,
-        NodeApprovalResponse::Denied => {
-            if let Some(connection) = connection {
-                connection.close(0_u32.into(), b"denied");
-            }
-            log::info!("Denied connection to {}", node_id.fmt_short());
+        Err(_) => {
+            // Dropping the receiver removes the request from the queue.
+            close(b"approval timed out");
+            log::info!("Approval for {} timed out", name);
             return false;
         }
     };
 
+    log::info!("Got response");
+
+    let role = approval.role;
+
+    let _ = state.approved_nodes.insert_async(node_id, approval).await;
+
+    // Errors if there are no connections, which is fine.
     let _ = state
-        .approved_nodes
-        .insert_async(node_id, node_sharing)
-        .await;
+        .admin_commands
+        .send(AdminCommand::Approve { node_id, role });
+
+    true
+}
+
+// Record an approval request from a node, returning whether it has made too many recently.
+async fn too_many_approval_requests(state: &State, node_id: PublicKey) -> bool {
+    let now = tokio::time::Instant::now();
+
+    let mut requests = state
+        .approval_requests
+        .entry_async(node_id)
+        .await
+        .or_default();
+    let requests = requests.get_mut();
+
+    requests.retain(|requested_at| now.duration_since(*requested_at) < APPROVAL_REQUEST_WINDOW);
+
+    if requests.len() >= MAX_APPROVAL_REQUESTS {
+        return true;
+    }
+
+    requests.push(now);
+    false
+}
+
+// Approve a node if it meets the trust rules.
+async fn auto_approve(state: &State, node_id: PublicKey) -> bool {
+    let reason = match state
+        .trust
+        .reason_to_trust(node_id, &state.approved_nodes)
+        .await
+    {
+        Some(reason) => reason,
+        None => return false,
+    };
+
+    log::info!(
+        "Approved {} as {}: {}",
+        state.address_book.name(&node_id),
+        state.trust.role().as_str(),
+        reason
+    );
+
+    approve(state, node_id, state.trust.role()).await;
 
     true
 }
 
-pub async fn connect(state: State, addr: NodeAddr, referrer: Option<PublicKey>) {
+// Approve a node with a role, including any request for it that is waiting in the queue.
+// A node that's already approved keeps its sharing policy.
+pub async fn approve(state: &State, node_id: PublicKey, role: Role) {
+    state
+        .approved_nodes
+        .entry_async(node_id)
+        .await
+        .and_modify(|approval| approval.role = role)
+        .or_insert(Approval {
+            sharing: NodeSharingPolicy::AllExcept(Default::default()),
+            role,
+        });
+}
+
+pub async fn kick(state: &State, node_id: PublicKey) {
+    state.approved_nodes.remove_async(&node_id).await;
+    if let Some((_, connection)) = state.connections.remove_async(&node_id).await {
+        connection.close(0_u32.into(), b"kicked");
+    }
+}
+
+async fn apply_admin_command(state: &State, admin: PublicKey, command: AdminCommand) {
+    match command {
+        AdminCommand::Approve { node_id, role } => {
+            let max_role = state.trust.admin_max_role();
+            if role > max_role {
+                log::warn!(
+                    "{} tried to approve {} as {}, approving as {} instead",
+                    state.address_book.name(&admin),
+                    state.address_book.name(&node_id),
+                    role.as_str(),
+                    max_role.as_str()
+                );
+            }
+            let role = role.min(max_role);
+
+            log::info!(
+                "{} approved {} as {}",
+                state.address_book.name(&admin),
+                state.address_book.name(&node_id),
+                role.as_str()
+            );
+            approve(state, node_id, role).await;
+        }
+        AdminCommand::Kick { node_id } => {
+            // Admins can't kick us or anyone we've made an admin.
+            if node_id == state.endpoint.node_id()
+                || state
+                    .approved_nodes
+                    .read_async(&node_id, |_, approval| approval.role == Role::Admin)
+                    .await
+                    == Some(true)
+            {
+                log::warn!(
+                    "Ignoring {} kicking {}",
+                    state.address_book.name(&admin),
+                    state.address_book.name(&node_id)
+                );
+                return;
+            }
+
+            log::info!(
+                "{} kicked {}",
+                state.address_book.name(&admin),
+                state.address_book.name(&node_id)
+            );
+            kick(state, node_id).await;
+        }
+    }
+}
+
+pub async fn connect(
+    state: State,
+    addr: NodeAddr,
+    referrer: Option<PublicKey>,
+    invite: Option<invite::Token>,
+) {
     let node_id = addr.node_id;
 
     let _node_connection = match NodeConnection::new(&state, node_id).await {
@@ -217,23 +563,106 @@ pub async fn connect(state: State, addr: NodeAddr, referrer: Option<PublicKey>)
             return;
         }
     } else {
+        // Nodes we connect to directly are hosting the session, but that's no reason to let
+        // them approve others on our behalf. An existing approval is kept as it is.
         let _ = state.approved_nodes.insert(
             addr.node_id,
-            NodeSharingPolicy::AllExcept(Default::default()),
+            Approval {
+                sharing: NodeSharingPolicy::AllExcept(Default::default()),
+                role: Role::Editor,
+            },
         );
     }
 
-    let connection = match state.endpoint.connect(addr, ALPN).await {
+    let connection = match state
+        .endpoint
+        .connect(addr, &rooms::alpn(&state.room))
+        .await
+    {
         Ok(connection) => connection,
         Err(error) => {
-            log::error!("Connecting to {} failed: {}", node_id, error);
+            log::error!(
+                "Connecting to {} failed: {}",
+                state.address_book.name(&node_id),
+                error
+            );
+            return;
+        }
+    };
+
+    let handshake = Handshake {
+        invite,
+        base_scene: (*state.base_scene).clone(),
+    };
+
+    let reply = match send_handshake(&connection, &handshake).await {
+        Ok(reply) => reply,
+        Err(error) => {
+            log::error!(
+                "Sending handshake to {} failed: {}",
+                state.address_book.name(&node_id),
+                error
+            );
             return;
         }
     };
 
+    if !check_base_scene(&state, node_id, &reply.base_scene) {
+        connection.close(0_u32.into(), b"base scene mismatch");
+        return;
+    }
+
     handle_connection(state, connection, node_id).await;
 }
 
+async fn send_handshake(
+    connection: &quinn::Connection,
+    handshake: &Handshake,
+) -> anyhow::Result<HandshakeReply> {
+    let (mut send, mut receive) = connection.open_bi().await?;
+    send.write_all(&postcard::to_stdvec(handshake)?).await?;
+    send.finish().await?;
+    let data = tokio::time::timeout(HANDSHAKE_TIMEOUT, receive.read_to_end(64 * 1024)).await??;
+    Ok(postcard::from_bytes(&data)?)
+}
+
+async fn receive_handshake(
+    state: &State,
+    connection: &quinn::Connection,
+) -> anyhow::Result<Handshake> {
+    let (mut send, mut receive) =
+        tokio::time::timeout(HANDSHAKE_TIMEOUT, connection.accept_bi()).await??;
+    let data = receive.read_to_end(64 * 1024).await?;
+    let handshake = postcard::from_bytes(&data)?;
+    let reply = HandshakeReply {
+        base_scene: (*state.base_scene).clone(),
+    };
+    send.write_all(&postcard::to_stdvec(&reply)?).await?;
+    send.finish().await?;
+    Ok(handshake)
+}
+
+// Whether to carry on with a node, given its base scene.
+fn check_base_scene(state: &State, node_id: PublicKey, base_scene: &BaseScene) -> bool {
+    let mismatch = match state.base_scene.mismatch(base_scene) {
+        Some(mismatch) => mismatch,
+        None => return true,
+    };
+
+    let name = state.address_book.name(&node_id);
+
+    match state.base_mismatch {
+        MismatchPolicy::Warn => {
+            log::warn!("{} has a different base scene: {}", name, mismatch);
+            true
+        }
+        MismatchPolicy::Refuse => {
+            log::error!("Refused {}: different base scene: {}", name, mismatch);
+            false
+        }
+    }
+}
+
 async fn handle_connection(
     state: State,
     connection: quinn::Connection,
@@ -255,49 +684,61 @@ async fn handle_connection(
 
     for existing_node_id in existing_node_ids {
         match state.approved_nodes.get_async(&existing_node_id).await {
-            Some(sharing_policy) => {
-                if !sharing_policy.get().allows(connection_node_id) {
-                    log::info!("Not sharing {} to {}", existing_node_id, connection_node_id);
+            Some(approval) => {
+                if !approval.get().sharing.allows(connection_node_id) {
+                    log::info!(
+                        "Not sharing {} to {}",
+                        state.address_book.name(&existing_node_id),
+                        state.address_book.name(&connection_node_id)
+                    );
                     continue;
                 }
             }
             None => {
-                log::error!("Node {} connected but not allowed.", existing_node_id);
-                continue;
-            }
-        }
-
-        let connection_info = match state.endpoint.connection_info(existing_node_id).await {
-            Err(error) => {
                 log::error!(
-                    "Error getting connection info for {}: {}",
-                    existing_node_id,
-                    error
+                    "Node {} connected but not allowed.",
+                    state.address_book.name(&existing_node_id)
                 );
                 continue;
             }
-            Ok(None) => {
-                log::error!("No connection info for {} found.", existing_node_id);
-                continue;
-            }
-            Ok(Some(info)) => info,
-        };
+        }
 
-        third_parties.push(NodeAddr {
-            node_id: existing_node_id,
-            info: AddrInfo {
-                derp_url: connection_info.derp_url,
-                direct_addresses: connection_info.addrs.iter().map(|addr| addr.addr).collect(),
-            },
-        });
+        if let Some(node_addr) = node_addr(&state, existing_node_id).await {
+            third_parties.push(node_addr);
+        }
     }
 
-    log::info!("Sending {:?} to {}", third_parties, connection_node_id);
+    if let Some(node_addr) = node_addr(&state, connection_node_id).await {
+        state.address_book.update_last_addr(node_addr);
+    }
+
+    log::info!(
+        "Sending {:?} to {}",
+        third_parties,
+        state.address_book.name(&connection_node_id)
+    );
+
+    state
+        .connections
+        .entry_async(connection_node_id)
+        .await
+        .insert_entry(connection.clone());
+
+    let send_role = tokio::spawn({
+        let connection = connection.clone();
+        let state = state.clone();
+        async move {
+            if let Err(error) = send_role(connection, &state, connection_node_id).await {
+                log::error!("{}", error);
+            }
+        }
+    });
 
     let send_initial_third_parties = tokio::spawn({
         let connection = connection.clone();
+        let bandwidth = state.bandwidth.clone();
         async move {
-            if let Err(error) = send_third_parties(connection, third_parties).await {
+            if let Err(error) = send_third_parties(connection, third_parties, &bandwidth).await {
                 log::error!("{}", error);
             }
         }
@@ -313,24 +754,197 @@ async fn handle_connection(
         }
     });
 
+    let outgoing_presence = tokio::spawn({
+        let connection = connection.clone();
+        let presence = state.presence.clone();
+        let bandwidth = state.bandwidth.clone();
+        async move {
+            if let Err(error) =
+                handle_outgoing_watch(connection, PacketType::Presence, presence, &bandwidth).await
+            {
+                log::error!("{}", error);
+            }
+        }
+    });
+
+    let outgoing_chat = tokio::spawn({
+        let connection = connection.clone();
+        let chat = state.chat.subscribe();
+        let bandwidth = state.bandwidth.clone();
+        async move {
+            if let Err(error) = handle_outgoing_chat(connection, chat, &bandwidth).await {
+                log::error!("{}", error);
+            }
+        }
+    });
+
+    let outgoing_pose = tokio::spawn({
+        let connection = connection.clone();
+        let state = state.clone();
+        async move {
+            if let Err(error) = handle_outgoing_pose(connection, state, connection_node_id).await {
+                log::error!("{}", error);
+            }
+        }
+    });
+
+    let outgoing_interest = tokio::spawn({
+        let connection = connection.clone();
+        let interest = state.interest.clone();
+        let bandwidth = state.bandwidth.clone();
+        async move {
+            if let Err(error) =
+                handle_outgoing_watch(connection, PacketType::Interest, interest, &bandwidth).await
+            {
+                log::error!("{}", error);
+            }
+        }
+    });
+
+    let outgoing_presenter = tokio::spawn({
+        let connection = connection.clone();
+        let presenter = state.presenter.clone();
+        let bandwidth = state.bandwidth.clone();
+        async move {
+            if let Err(error) =
+                handle_outgoing_watch(connection, PacketType::Presenter, presenter, &bandwidth)
+                    .await
+            {
+                log::error!("{}", error);
+            }
+        }
+    });
+
+    let outgoing_admin = tokio::spawn({
+        let connection = connection.clone();
+        let state = state.clone();
+        async move {
+            if let Err(error) = handle_outgoing_admin(connection, state, connection_node_id).await {
+                log::error!("{}", error);
+            }
+        }
+    });
+
+    let outgoing_assets = tokio::spawn({
+        let connection = connection.clone();
+        let manifest = state.asset_manifest.clone();
+        let bandwidth = state.bandwidth.clone();
+        async move {
+            if let Err(error) =
+                handle_outgoing_watch(connection, PacketType::Assets, manifest, &bandwidth).await
+            {
+                log::error!("{}", error);
+            }
+        }
+    });
+
+    let asset_requests = tokio::spawn({
+        let connection = connection.clone();
+        let state = state.clone();
+        async move {
+            if let Err(error) = handle_asset_requests(connection, state).await {
+                log::error!("{}", error);
+            }
+        }
+    });
+
+    let outgoing_relayed = state.relay.then(|| {
+        tokio::spawn({
+            let connection = connection.clone();
+            let state = state.clone();
+            async move {
+                if let Err(error) =
+                    handle_outgoing_relayed(connection, state, connection_node_id).await
+                {
+                    log::error!("{}", error);
+                }
+            }
+        })
+    });
+
     let outgoing = tokio::spawn({
+        let state = state.clone();
         async move {
-            if let Err(error) = handle_outgoing(connection, state).await {
+            if let Err(error) = handle_outgoing(connection, state, connection_node_id).await {
                 log::error!("{}", error);
             }
         }
     });
 
+    let _ = send_role.await;
     let _ = send_initial_third_parties.await;
     let _ = incoming.await;
+    // Presence, chat, poses, presenting and interest only change occasionally, so don't wait
+    // for a failed write to notice the connection closing.
+    outgoing_presence.abort();
+    outgoing_chat.abort();
+    outgoing_pose.abort();
+    outgoing_presenter.abort();
+    outgoing_interest.abort();
+    outgoing_admin.abort();
+    outgoing_assets.abort();
+    asset_requests.abort();
+    if let Some(outgoing_relayed) = outgoing_relayed {
+        outgoing_relayed.abort();
+    }
     let _ = outgoing.await;
 
-    log::info!("Finished handling the connection to {}", connection_node_id);
+    state.connections.remove_async(&connection_node_id).await;
+    state.granted_roles.remove_async(&connection_node_id).await;
+
+    state.participants.remove_async(&connection_node_id).await;
+    state.avatar_poses.remove_async(&connection_node_id).await;
+    state
+        .unresolved_avatars
+        .remove_async(&connection_node_id)
+        .await;
+    state.presenters.remove_async(&connection_node_id).await;
+    state.interests.remove_async(&connection_node_id).await;
+    state.remote_assets.remove_async(&connection_node_id).await;
+
+    log::info!(
+        "Finished handling the connection to {}",
+        state.address_book.name(&connection_node_id)
+    );
+}
+
+async fn node_addr(state: &State, node_id: PublicKey) -> Option<NodeAddr> {
+    let connection_info = match state.endpoint.connection_info(node_id).await {
+        Err(error) => {
+            log::error!(
+                "Error getting connection info for {}: {}",
+                state.address_book.name(&node_id),
+                error
+            );
+            return None;
+        }
+        Ok(None) => {
+            log::error!(
+                "No connection info for {} found.",
+                state.address_book.name(&node_id)
+            );
+            return None;
+        }
+        Ok(Some(info)) => info,
+    };
+
+    Some(node_addr_from_info(&connection_info))
+}
+
+pub fn node_addr_from_info(connection_info: &iroh_net::magicsock::EndpointInfo) -> NodeAddr {
+    NodeAddr {
+        node_id: connection_info.public_key,
+        info: AddrInfo {
+            derp_url: connection_info.derp_url.clone(),
+            direct_addresses: connection_info.addrs.iter().map(|addr| addr.addr).collect(),
+        },
+    }
 }
 
 async fn send_third_parties(
     connection: quinn::Connection,
     third_parties: Vec<NodeAddr>,
+    bandwidth: &Bandwidth,
 ) -> anyhow::Result<()> {
     if third_parties.is_empty() {
         return Ok(());
@@ -338,41 +952,120 @@ async fn send_third_parties(
 
     let mut stream = connection.open_uni().await?;
 
-    stream.write_all(&[PacketType::NewNode as u8]).await?;
-
     let third_parties = postcard::to_stdvec(&third_parties)?;
 
-    stream.write_all(&third_parties).await?;
+    write_packet(&mut stream, PacketType::NewNode, &third_parties, bandwidth).await?;
 
     log::info!("Sent third parties");
 
     Ok(())
 }
 
+async fn send_role(
+    connection: quinn::Connection,
+    state: &State,
+    node_id: PublicKey,
+) -> anyhow::Result<()> {
+    let role = match state
+        .approved_nodes
+        .read_async(&node_id, |_, approval| approval.role)
+        .await
+    {
+        Some(role) => role,
+        None => return Ok(()),
+    };
+
+    let mut stream = connection.open_uni().await?;
+    write_packet(
+        &mut stream,
+        PacketType::Role,
+        &postcard::to_stdvec(&role)?,
+        &state.bandwidth,
+    )
+    .await?;
+
+    Ok(())
+}
+
+async fn write_packet(
+    stream: &mut quinn::SendStream,
+    packet_type: PacketType,
+    payload: &[u8],
+    bandwidth: &Bandwidth,
+) -> anyhow::Result<()> {
+    stream.write_all(&[packet_type as u8]).await?;
+    stream.write_all(payload).await?;
+    bandwidth.record(packet_type, 1 + payload.len());
+    Ok(())
+}
+
 async fn write_data_packet(
     stream: &mut quinn::SendStream,
-    index: u32,
-    update_index: u32,
-    state: &cpp::String,
+    layer: &SignedLayer,
+    bandwidth: &Bandwidth,
 ) -> anyhow::Result<()> {
     stream.write_all(&[PacketType::Data as u8]).await?;
-    stream.write_all(&index.to_le_bytes()).await?;
-    stream.write_all(&update_index.to_le_bytes()).await?;
-    stream.write_all(state.as_bytes()).await?;
+    stream.write_all(&layer.header()).await?;
+    stream.write_all(&layer.data).await?;
+    bandwidth.record(
+        PacketType::Data,
+        1 + SignedLayer::HEADER_LEN + layer.data.len(),
+    );
     Ok(())
 }
 
-async fn handle_outgoing(connection: quinn::Connection, mut state: State) -> anyhow::Result<()> {
+// Nodes that made us a viewer only take our avatar from our layers, so don't send them
+// anything else.
+fn outgoing_layer(state: &State, node_id: PublicKey, layer: cpp::String) -> cpp::String {
+    let can_edit = state
+        .granted_roles
+        .read(&node_id, |_, role| role.can_edit())
+        .unwrap_or(true);
+
+    if can_edit {
+        return layer;
+    }
+
+    let (filtered, _) =
+        PathAcl::own_avatar_only().filter_usda(state.endpoint.node_id(), layer.as_str());
+    cpp::String::new(&filtered)
+}
+
+async fn handle_outgoing(
+    connection: quinn::Connection,
+    mut state: State,
+    node_id: PublicKey,
+) -> anyhow::Result<()> {
     spawn_fallible(
         {
             let connection = connection.clone();
-            let layers = state.state.borrow().layers.clone();
+            let mut session_layers = state.session_layers.clone();
+            let state = state.clone();
             async move {
-                for (index, layer) in layers.iter().enumerate() {
-                    println!("{}: {}", index, layer.as_str());
+                // Hosted rooms have no layers of our own, so this may never finish.
+                let (layers, update_indices) = tokio::select! {
+                    layers = session_layers.wait_for(|layers| !layers.layers.is_empty()) => {
+                        let layers = layers?;
+                        (layers.layers.clone(), layers.layer_update_indices.clone())
+                    }
+                    _ = connection.closed() => return Ok(()),
+                };
+                // With the update index each layer was last changed in, so that peers that
+                // already have them drop them.
+                for (index, (layer, update_index)) in
+                    layers.into_iter().zip(update_indices).enumerate()
+                {
+                    let layer = outgoing_layer(&state, node_id, layer);
                     let mut stream = connection.open_uni().await?;
                     stream.set_priority(i32::max_value().saturating_sub(index as i32))?;
-                    write_data_packet(&mut stream, index as u32, u32::max_value(), layer).await?;
+                    let layer = SignedLayer::sign(
+                        state.endpoint.secret_key(),
+                        signed_layer::session_epoch(),
+                        index as u32,
+                        update_index,
+                        layer.as_bytes().to_vec(),
+                    );
+                    write_data_packet(&mut stream, &layer, &state.bandwidth).await?;
                 }
 
                 log::info!("Sent initial layers");
@@ -387,6 +1080,9 @@ async fn handle_outgoing(connection: quinn::Connection, mut state: State) -> any
 
     let (error_tx, mut error_rx) = mpsc::channel(1);
 
+    // Layers are sent for every update after this, however many arrive between wakeups.
+    let mut sent_update_index = state.session_layers.borrow_and_update().update_index;
+
     loop {
         match error_rx.try_recv() {
             Ok(error) => return Err(error),
@@ -396,26 +1092,551 @@ async fn handle_outgoing(connection: quinn::Connection, mut state: State) -> any
             Err(mpsc::error::TryRecvError::Empty) => {}
         }
 
-        state.state.changed().await?;
-        let (layer, index, update_index) = {
-            let state = state.state.borrow();
-            (
-                state.layers[state.updated_layer].clone(),
-                state.updated_layer,
-                state.update_index,
+        state.session_layers.changed().await?;
+        let updated_layers: Vec<_> = {
+            let layers = state.session_layers.borrow_and_update();
+            let updated_layers = layers
+                .updated_since(sent_update_index)
+                .map(|index| {
+                    (
+                        layers.layers[index].clone(),
+                        index,
+                        layers.layer_update_indices[index],
+                    )
+                })
+                .collect();
+            sent_update_index = layers.update_index;
+            updated_layers
+        };
+
+        for (layer, index, update_index) in updated_layers {
+            let layer = SignedLayer::sign(
+                state.endpoint.secret_key(),
+                signed_layer::session_epoch(),
+                index as u32,
+                update_index,
+                outgoing_layer(&state, node_id, layer).as_bytes().to_vec(),
+            );
+            let error_tx = error_tx.clone();
+            let connection = connection.clone();
+            let bandwidth = state.bandwidth.clone();
+            spawn_fallible(
+                async move {
+                    let mut stream = connection.open_uni().await?;
+                    stream.set_priority(update_index as i32)?;
+                    write_data_packet(&mut stream, &layer, &bandwidth).await?;
+                    Ok(())
+                },
+                |error| async move {
+                    let _ = error_tx.send(error).await;
+                },
+            );
+        }
+    }
+}
+
+// Send the current value, then every change to it.
+async fn handle_outgoing_watch<T: serde::Serialize>(
+    connection: quinn::Connection,
+    packet_type: PacketType,
+    mut receiver: watch::Receiver<T>,
+    bandwidth: &Bandwidth,
+) -> anyhow::Result<()> {
+    loop {
+        let serialized = postcard::to_stdvec(&*receiver.borrow_and_update())?;
+
+        let mut stream = connection.open_uni().await?;
+        write_packet(&mut stream, packet_type, &serialized, bandwidth).await?;
+
+        receiver.changed().await?;
+    }
+}
+
+// Like `handle_outgoing_watch`, but throttled by the distance between our avatar and the
+// node's area of interest.
+async fn handle_outgoing_pose(
+    connection: quinn::Connection,
+    state: State,
+    node_id: PublicKey,
+) -> anyhow::Result<()> {
+    // How often to re-check the interval when we aren't moving, as the node might be.
+    const RECHECK_INTERVAL: std::time::Duration = std::time::Duration::from_millis(500);
+
+    let mut pose = state.pose.clone();
+    let mut sent: Option<(PoseSample, tokio::time::Instant)> = None;
+
+    loop {
+        let sample = *pose.borrow_and_update();
+
+        let interval = if state.presenter.borrow().is_some() {
+            // Everyone following a presentation needs the presenter's camera.
+            Some(std::time::Duration::ZERO)
+        } else {
+            interest::send_interval(
+                &state.interests,
+                &state.avatar_poses,
+                &state.layer_poses,
+                &node_id,
+                &sample.pose,
             )
         };
-        let error_tx = error_tx.clone();
-        let connection = connection.clone();
+
+        let already_sent = sent.map_or(false, |(sent_sample, _)| sent_sample == sample);
+
+        if let (Some(interval), false) = (interval, already_sent) {
+            if let Some((_, sent_at)) = sent {
+                tokio::time::sleep_until(sent_at + interval).await;
+            }
+
+            // Send whatever is latest after waiting.
+            let sample = *pose.borrow_and_update();
+            let serialized = postcard::to_stdvec(&sample)?;
+
+            let mut stream = connection.open_uni().await?;
+            write_packet(&mut stream, PacketType::Pose, &serialized, &state.bandwidth).await?;
+
+            sent = Some((sample, tokio::time::Instant::now()));
+        }
+
+        if let Ok(result) = tokio::time::timeout(RECHECK_INTERVAL, pose.changed()).await {
+            result?;
+        }
+    }
+}
+
+async fn write_relayed_packet(
+    stream: &mut quinn::SendStream,
+    hops: u8,
+    layer: &SignedLayer,
+    bandwidth: &Bandwidth,
+) -> anyhow::Result<()> {
+    stream
+        .write_all(&[PacketType::RelayedData as u8, hops])
+        .await?;
+    stream.write_all(&layer.header()).await?;
+    stream.write_all(&layer.data).await?;
+    bandwidth.record(
+        PacketType::RelayedData,
+        2 + SignedLayer::HEADER_LEN + layer.data.len(),
+    );
+    Ok(())
+}
+
+// Forward other nodes' layers, starting with the latest ones we have.
+async fn handle_outgoing_relayed(
+    connection: quinn::Connection,
+    state: State,
+    node_id: PublicKey,
+) -> anyhow::Result<()> {
+    let mut relayed_layers = state.relayed_layers.subscribe();
+
+    for layer in relay::stored_layers(&state.remote_authors).await {
+        if layer.author == node_id {
+            continue;
+        }
+
+        let mut stream = connection.open_uni().await?;
+        write_relayed_packet(&mut stream, 1, &layer, &state.bandwidth).await?;
+    }
+
+    loop {
+        let relayed = match relayed_layers.recv().await {
+            Ok(relayed) => relayed,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                log::warn!("Skipped relaying {} layers", skipped);
+                continue;
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        if relayed.from == node_id || relayed.layer.author == node_id {
+            continue;
+        }
+
+        let mut stream = connection.open_uni().await?;
+        write_relayed_packet(&mut stream, relayed.hops, &relayed.layer, &state.bandwidth).await?;
+    }
+}
+
+// Forward our approvals and kicks to nodes that made us an admin.
+async fn handle_outgoing_admin(
+    connection: quinn::Connection,
+    state: State,
+    node_id: PublicKey,
+) -> anyhow::Result<()> {
+    let mut commands = state.admin_commands.subscribe();
+
+    loop {
+        let command = match commands.recv().await {
+            Ok(command) => command,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                log::warn!("Skipped sending {} admin commands", skipped);
+                continue;
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        let is_admin = state
+            .granted_roles
+            .read_async(&node_id, |_, role| *role == Role::Admin)
+            .await
+            .unwrap_or(false);
+
+        let target = match &command {
+            AdminCommand::Approve { node_id, .. } | AdminCommand::Kick { node_id } => *node_id,
+        };
+
+        if !is_admin || target == node_id {
+            continue;
+        }
+
+        let serialized = postcard::to_stdvec(&command)?;
+
+        let mut stream = connection.open_uni().await?;
+        write_packet(
+            &mut stream,
+            PacketType::Admin,
+            &serialized,
+            &state.bandwidth,
+        )
+        .await?;
+    }
+}
+
+async fn handle_outgoing_chat(
+    connection: quinn::Connection,
+    mut chat: broadcast::Receiver<ChatMessage>,
+    bandwidth: &Bandwidth,
+) -> anyhow::Result<()> {
+    loop {
+        let message = match chat.recv().await {
+            Ok(message) => message,
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                log::warn!("Skipped sending {} chat messages", skipped);
+                continue;
+            }
+            Err(error) => return Err(error.into()),
+        };
+
+        let serialized = postcard::to_stdvec(&message)?;
+
+        let mut stream = connection.open_uni().await?;
+        write_packet(&mut stream, PacketType::Chat, &serialized, bandwidth).await?;
+    }
+}
+
+// Apply a layer received from `from` to its author's sublayers. Returns false if the layer
+// was already applied, or is older than one that was.
+async fn apply_layer(state: &State, from: PublicKey, layer: &SignedLayer) -> anyhow::Result<bool> {
+    // Each author gets a sublayer of the root, and anyone can make up keys to sign with.
+    if !state.remote_authors.contains_async(&layer.author).await
+        && !state.approved_nodes.contains_async(&layer.author).await
+        && state.remote_authors.len() >= relay::MAX_REMOTE_AUTHORS
+    {
+        log::warn!(
+            "Ignoring a layer from {}, relayed by {}, as there are too many authors",
+            state.address_book.name(&layer.author),
+            state.address_book.name(&from)
+        );
+        return Ok(false);
+    }
+
+    let remote_author = relay::remote_author(&state.remote_authors, &state.usd, layer.author).await;
+    let mut author_layers = remote_author.layers.lock().await;
+
+    if !author_layers.is_new(layer) {
+        return Ok(false);
+    }
+
+    let acl = layer_acl(state, layer.author).await;
+    import_layer(state, from, &remote_author, &mut author_layers, layer, &acl).await?;
+
+    author_layers.store(layer.clone());
+
+    Ok(true)
+}
+
+// Apply a layer saved in an earlier session. Layers are saved as they were signed, so it's
+// limited by the author's permissions in this session, like a layer that was just received.
+pub async fn restore_layer(state: &State, layer: &SignedLayer) -> anyhow::Result<()> {
+    layer.verify()?;
+
+    let remote_author = relay::remote_author(&state.remote_authors, &state.usd, layer.author).await;
+    let mut author_layers = remote_author.layers.lock().await;
+
+    let acl = layer_acl(state, layer.author).await;
+    import_layer(
+        state,
+        layer.author,
+        &remote_author,
+        &mut author_layers,
+        layer,
+        &acl,
+    )
+    .await?;
+
+    author_layers.store(layer.clone());
+
+    Ok(())
+}
+
+// Import the latest version of an author's layers again, so that assets fetched since
+// resolve.
+async fn reload_layers(state: &State, from: PublicKey, author: PublicKey) -> anyhow::Result<()> {
+    // Assets in a manifest are fetched before any layer references them, and there's nothing
+    // to reload for an author that hasn't sent any.
+    let remote_author = match state
+        .remote_authors
+        .read_async(&author, |_, remote_author| remote_author.clone())
+        .await
+    {
+        Some(remote_author) => remote_author,
+        None => return Ok(()),
+    };
+    let mut author_layers = remote_author.layers.lock().await;
+
+    let acl = layer_acl(state, author).await;
+    let layers: Vec<SignedLayer> = author_layers.latest().cloned().collect();
+    for layer in layers {
+        import_layer(
+            state,
+            from,
+            &remote_author,
+            &mut author_layers,
+            &layer,
+            &acl,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+// What an author's layers may contain. It's the author's permissions that count, not those
+// of the node that relayed them, so that a node can't get around being kicked or denied by
+// having its layers relayed. Authors we haven't approved can only move their own avatar.
+async fn layer_acl(state: &State, author: PublicKey) -> PathAcl {
+    let can_edit = state
+        .approved_nodes
+        .read_async(&author, |_, approval| approval.role.can_edit())
+        .await
+        .unwrap_or(false);
+
+    if can_edit {
+        state
+            .acls
+            .read_async(&author, |_, acl| acl.clone())
+            .await
+            .unwrap_or_else(|| state.default_acl.clone())
+    } else {
+        PathAcl::own_avatar_only()
+    }
+}
+
+async fn import_layer(
+    state: &State,
+    from: PublicKey,
+    remote_author: &RemoteAuthor,
+    author_layers: &mut AuthorLayers,
+    layer: &SignedLayer,
+    acl: &PathAcl,
+) -> anyhow::Result<()> {
+    let (string, missing_assets) = state
+        .assets
+        .to_local_paths(std::str::from_utf8(&layer.data)?);
+
+    if let Some(pose) = avatars::layer_pose(layer.author, &string) {
+        state
+            .layer_poses
+            .entry_async(layer.author)
+            .await
+            .insert_entry(pose);
+    }
+
+    // Only the layer with the avatar's reference says whether it resolves.
+    if let Some(missing) = avatars::missing_avatar_assets(layer.author, &string) {
+        if missing.is_empty() {
+            state.unresolved_avatars.remove_async(&layer.author).await;
+        } else if state
+            .unresolved_avatars
+            .insert_async(layer.author)
+            .await
+            .is_ok()
+        {
+            log::warn!(
+                "The avatar of {} doesn't resolve ({}), showing a placeholder",
+                state.address_book.name(&layer.author),
+                missing.join(", ")
+            );
+        }
+    }
+
+    let stripped = {
+        let _lock = state.usd.write().await;
+        layers::update_remote_sublayers(
+            &remote_author.root,
+            &mut author_layers.sublayers,
+            layer.index as _,
+            &string,
+            layer.author,
+            acl,
+        )?
+    };
+
+    if !stripped.is_empty() {
+        log::warn!(
+            "Stripped specs that {} isn't allowed to author: {}",
+            state.address_book.name(&layer.author),
+            stripped.join(", ")
+        );
+    }
+
+    // Whoever sent us the layer is the most likely to have its assets.
+    if let Some(connection) = state
+        .connections
+        .read_async(&from, |_, connection| connection.clone())
+        .await
+    {
+        for asset in missing_assets {
+            if state.assets.start_fetch(asset.hash) {
+                spawn_fetch_asset(state.clone(), connection.clone(), from, asset, layer.author);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+// Reload an author's layers after a moment, so that assets fetched around the same time only
+// cause one reload.
+fn schedule_reload(state: &State, from: PublicKey, author: PublicKey) {
+    if state.pending_reloads.insert(author).is_err() {
+        return;
+    }
+
+    let state = state.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(RELOAD_DELAY).await;
+        state.pending_reloads.remove_async(&author).await;
+
+        if let Err(error) = reload_layers(&state, from, author).await {
+            log::error!("{}", error);
+        }
+    });
+}
+
+// Fetch an asset and then reload the layers of the author that references it.
+fn spawn_fetch_asset(
+    state: State,
+    connection: quinn::Connection,
+    node_id: PublicKey,
+    asset: AssetRef,
+    author: PublicKey,
+) {
+    tokio::spawn(async move {
+        let name = state.address_book.name(&node_id);
+
+        let fetched = async {
+            let _slot = state.assets.fetch_slot().await?;
+            log::info!("Fetching {} from {}", asset.name, name);
+            fetch_asset(&state, &connection, &asset).await
+        }
+        .await;
+
+        state.assets.finish_fetch(&asset.hash);
+
+        if let Err(error) = fetched {
+            log::error!("Fetching {} from {} failed: {}", asset.name, name, error);
+            return;
+        }
+
+        log::info!("Fetched {} from {}", asset.name, name);
+
+        schedule_reload(&state, node_id, author);
+    });
+}
+
+// Assets are requested on a bi stream of their own, by hash. The reply is the size of the
+// asset followed by its contents, with a size of zero meaning the node doesn't have it.
+async fn fetch_asset(
+    state: &State,
+    connection: &quinn::Connection,
+    asset: &AssetRef,
+) -> anyhow::Result<()> {
+    let (mut send, mut receive) = connection.open_bi().await?;
+    send.write_all(&asset.hash).await?;
+    send.finish().await?;
+
+    let mut size = [0; 8];
+    receive.read_exact(&mut size).await?;
+    let size = u64::from_le_bytes(size) as usize;
+    if size == 0 {
+        return Err(anyhow::anyhow!("The node doesn't have it"));
+    }
+
+    let _reserved = state.assets.reserve_size(size).await?;
+
+    // Written to disk as it arrives rather than held in memory.
+    let mut partial = state.assets.create_partial(asset).await?;
+    let received = async {
+        let mut buffer = vec![0; 64 * 1024];
+        let mut remaining = size;
+        while remaining > 0 {
+            let read = receive
+                .read(&mut buffer[..remaining.min(buffer.len())])
+                .await?
+                .ok_or_else(|| anyhow::anyhow!("The node sent less than it said it would"))?;
+            partial.write(&buffer[..read]).await?;
+            remaining -= read;
+        }
+        anyhow::Ok(())
+    }
+    .await;
+
+    if let Err(error) = received {
+        partial.discard().await;
+        return Err(error);
+    }
+
+    state.bandwidth.record(PacketType::Assets, size);
+    partial.finish().await?;
+    Ok(())
+}
+
+// Only assets that we've shared or fetched are sent, so peers can't read arbitrary files.
+async fn handle_asset_requests(connection: quinn::Connection, state: State) -> anyhow::Result<()> {
+    loop {
+        let (mut send, mut receive) = connection.accept_bi().await?;
+        let state = state.clone();
         spawn_fallible(
             async move {
-                let mut stream = connection.open_uni().await?;
-                stream.set_priority(update_index as i32)?;
-                write_data_packet(&mut stream, index as u32, update_index as u32, &layer).await?;
+                let mut hash: AssetHash = [0; 32];
+                receive.read_exact(&mut hash).await?;
+
+                let file = match state.assets.path_of(&hash) {
+                    Some(path) => tokio::fs::File::open(path).await.ok(),
+                    None => None,
+                };
+
+                let sent = match file {
+                    Some(file) => {
+                        let size = file.metadata().await?.len();
+                        send.write_all(&size.to_le_bytes()).await?;
+                        // Streamed from disk, and no more than the size that was sent.
+                        tokio::io::copy(&mut file.take(size), &mut send).await?
+                    }
+                    None => {
+                        send.write_all(&0_u64.to_le_bytes()).await?;
+                        0
+                    }
+                };
+
+                send.finish().await?;
+                state.bandwidth.record(PacketType::Assets, sent as usize);
                 Ok(())
             },
             |error| async move {
-                let _ = error_tx.send(error).await;
+                log::error!("{}", error);
             },
         );
     }
@@ -426,25 +1647,22 @@ async fn handle_incoming(
     node_id: PublicKey,
     connection: quinn::Connection,
 ) -> anyhow::Result<()> {
-    let mut remote_sublayers = Arc::new(tokio::sync::Mutex::new(Vec::new()));
-    let latest_update = Arc::new(atomic::AtomicU32::new(0));
+    let remote_author = relay::remote_author(&state.remote_authors, &state.usd, node_id).await;
 
-    let remote_root_layer = Arc::new(bbl_usd::sdf::Layer::create_anonymous(".usda"));
-    state
-        .usd
-        .write()
-        .await
-        .root_layer
-        .insert_sub_layer_path(remote_root_layer.get_identifier(), 0);
+    let presence_layer = Arc::new(bbl_usd::sdf::Layer::create_anonymous(".usda"));
+    {
+        let _lock = state.usd.write().await;
+        remote_author
+            .root
+            .insert_sub_layer_path(presence_layer.get_identifier(), 0);
+    }
 
     log::info!("Created initial root layer");
 
     loop {
         let mut stream = connection.accept_uni().await?;
-        let remote_sublayers = remote_sublayers.clone();
-        let remote_root_layer = remote_root_layer.clone();
+        let presence_layer = presence_layer.clone();
         let state = state.clone();
-        let latest_update = latest_update.clone();
         spawn_fallible(
             async move {
                 let ty = {
@@ -456,60 +1674,211 @@ async fn handle_incoming(
                         .ok_or_else(|| anyhow::anyhow!("Got invalid packet byte: {}", ty_byte))?
                 };
                 match ty {
-                    PacketType::Data => {
-                        let mut index = [0_u8; 4];
-                        stream.read_exact(&mut index).await?;
-                        let index = u32::from_le_bytes(index);
-
-                        let mut update_index = [0_u8; 4];
-                        stream.read_exact(&mut update_index).await?;
-                        let update_index = u32::from_le_bytes(update_index);
-
-                        if update_index != u32::max_value() {
-                            let prev_latest =
-                                latest_update.fetch_max(update_index, atomic::Ordering::Relaxed);
-                            if prev_latest >= update_index {
-                                log::info!(
-                                    "prev_latest: {prev_latest}, update_index: {update_index}, skipping"
-                                );
-                                return Ok(());
-                            }
-                        }
-
+                    PacketType::Data | PacketType::RelayedData => {
+                        let hops = if let PacketType::RelayedData = ty {
+                            let mut hops = 0_u8;
+                            stream.read_exact(std::slice::from_mut(&mut hops)).await?;
+                            hops
+                        } else {
+                            0
+                        };
+
+                        let mut header = [0_u8; SignedLayer::HEADER_LEN];
+                        stream.read_exact(&mut header).await?;
                         let data = stream.read_to_end(1024 * 1024).await?;
-                        let string = std::str::from_utf8(&data)?;
-                        let cpp_string = bbl_usd::cpp::String::new(string);
+                        let layer = SignedLayer::from_header(&header, data)?;
+
+                        layer.verify()?;
 
+                        if let PacketType::Data = ty {
+                            if layer.author != node_id {
+                                return Err(anyhow::anyhow!(
+                                    "{} sent a layer authored by {}",
+                                    state.address_book.name(&node_id),
+                                    state.address_book.name(&layer.author)
+                                ));
+                            }
+                        } else if !state.relay
+                            || layer.author == state.endpoint.node_id()
+                            || hops >= relay::MAX_HOPS
                         {
-                            let _lock = state.usd.write().await;
-                            let mut remote_sublayers = remote_sublayers.lock().await;
-                            layers::update_remote_sublayers(
-                                &remote_root_layer,
-                                &mut remote_sublayers,
-                                index as _,
-                                &cpp_string,
-                            )?;
+                            return Ok(());
                         }
 
-                        //log::info!("Got {:?} bytes from {}", string, node_id);
+                        if apply_layer(&state, node_id, &layer).await? && state.relay {
+                            // Errors if there are no connections, which is fine.
+                            let _ = state.relayed_layers.send(RelayedLayer {
+                                from: node_id,
+                                hops: hops + 1,
+                                layer,
+                            });
+                        }
                     }
                     PacketType::NewNode => {
                         let data = stream.read_to_end(1024 * 1024).await?;
                         let third_parties: Vec<NodeAddr> = postcard::from_bytes(&data)?;
                         for node_addr in third_parties.into_iter() {
+                            state
+                                .trust
+                                .record_introduction(node_addr.node_id, node_id)
+                                .await;
+
+                            // An introduction can be what makes a node that is already
+                            // waiting for approval trusted.
+                            if !state
+                                .approved_nodes
+                                .contains_async(&node_addr.node_id)
+                                .await
+                            {
+                                auto_approve(&state, node_addr.node_id).await;
+                            }
+
                             fn spawn_connect(
                                 state: State,
                                 node_addr: NodeAddr,
                                 referrer: PublicKey,
                             ) {
                                 tokio::spawn(async move {
-                                    connect(state, node_addr, Some(referrer)).await;
+                                    connect(state, node_addr, Some(referrer), None).await;
                                 });
                             }
 
                             spawn_connect(state.clone(), node_addr, node_id);
                         }
                     }
+                    PacketType::Presence => {
+                        let data = stream.read_to_end(64 * 1024).await?;
+                        let presence: Presence = postcard::from_bytes(&data)?;
+                        let usda = cpp::String::new(&presence.to_usda(node_id));
+
+                        {
+                            let _lock = state.usd.write().await;
+                            if !presence_layer.import_from_str(&usda) {
+                                return Err(anyhow::anyhow!(
+                                    "Import of presence from {} failed.",
+                                    state.address_book.name(&node_id)
+                                ));
+                            }
+                        }
+
+                        state
+                            .participants
+                            .entry_async(node_id)
+                            .await
+                            .insert_entry(presence);
+                    }
+                    PacketType::Chat => {
+                        let data = stream.read_to_end(64 * 1024).await?;
+                        let message: ChatMessage = postcard::from_bytes(&data)?;
+                        log::info!(
+                            "[chat] {}: {}",
+                            state.address_book.name(&node_id),
+                            message.escaped_text()
+                        );
+                        state.chat.receive(node_id, message);
+                    }
+                    PacketType::Pose => {
+                        let data = stream.read_to_end(1024).await?;
+                        let sample: PoseSample = postcard::from_bytes(&data)?;
+                        state
+                            .avatar_poses
+                            .entry_async(node_id)
+                            .await
+                            .or_default()
+                            .get_mut()
+                            .push(sample);
+                    }
+                    PacketType::Interest => {
+                        let data = stream.read_to_end(1024).await?;
+                        let interest: AreaOfInterest = postcard::from_bytes(&data)?;
+                        state
+                            .interests
+                            .entry_async(node_id)
+                            .await
+                            .insert_entry(interest);
+                    }
+                    PacketType::Presenter => {
+                        let data = stream.read_to_end(1024).await?;
+                        let presenter: Option<PresenterState> = postcard::from_bytes(&data)?;
+                        let name = state.address_book.name(&node_id);
+                        match presenter {
+                            Some(presenter) => {
+                                let started = !state.presenters.contains_async(&node_id).await;
+                                state
+                                    .presenters
+                                    .entry_async(node_id)
+                                    .await
+                                    .insert_entry(presenter);
+                                if started {
+                                    log::info!("{} started presenting", name);
+                                }
+                            }
+                            None => {
+                                if state.presenters.remove_async(&node_id).await.is_some() {
+                                    log::info!("{} stopped presenting", name);
+                                }
+                            }
+                        }
+                    }
+                    PacketType::Role => {
+                        let data = stream.read_to_end(1024).await?;
+                        let role: Role = postcard::from_bytes(&data)?;
+                        log::info!(
+                            "{} made us {}",
+                            state.address_book.name(&node_id),
+                            role.as_str()
+                        );
+                        state
+                            .granted_roles
+                            .entry_async(node_id)
+                            .await
+                            .insert_entry(role);
+                    }
+                    PacketType::Admin => {
+                        let data = stream.read_to_end(1024).await?;
+                        let command: AdminCommand = postcard::from_bytes(&data)?;
+
+                        let is_admin = state
+                            .approved_nodes
+                            .read_async(&node_id, |_, approval| approval.role == Role::Admin)
+                            .await
+                            .unwrap_or(false);
+
+                        if !is_admin {
+                            return Err(anyhow::anyhow!(
+                                "Got an admin command from {}, who isn't an admin",
+                                state.address_book.name(&node_id)
+                            ));
+                        }
+
+                        apply_admin_command(&state, node_id, command).await;
+                    }
+                    PacketType::Assets => {
+                        let data = stream.read_to_end(1024 * 1024).await?;
+                        let manifest: AssetManifest = postcard::from_bytes(&data)?;
+
+                        // Fetch ahead of the layers that reference the assets.
+                        for (path, hash) in &manifest.assets {
+                            let asset = AssetRef::new(*hash, path);
+                            if state.assets.local_path(&asset).is_none()
+                                && state.assets.start_fetch(asset.hash)
+                            {
+                                spawn_fetch_asset(
+                                    state.clone(),
+                                    connection.clone(),
+                                    node_id,
+                                    asset,
+                                    node_id,
+                                );
+                            }
+                        }
+
+                        state
+                            .remote_assets
+                            .entry_async(node_id)
+                            .await
+                            .insert_entry(manifest);
+                    }
                 }
 
                 Ok(())
@@ -0,0 +1,80 @@
+use crate::util::{avatar_name, usda_string};
+use iroh_net::key::PublicKey;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+pub type Participants = Arc<scc::HashMap<PublicKey, Presence>>;
+
+#[derive(Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Status {
+    Available,
+    Busy,
+    Away,
+}
+
+impl Status {
+    pub const ALL: [Self; 3] = [Self::Available, Self::Busy, Self::Away];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Available => "available",
+            Self::Busy => "busy",
+            Self::Away => "away",
+        }
+    }
+}
+
+// Broadcast by each peer to everyone it is connected to.
+#[derive(Clone, PartialEq, Serialize, Deserialize)]
+pub struct Presence {
+    pub display_name: String,
+    // Linear RGB.
+    pub colour: [f32; 3],
+    pub avatar: String,
+    pub status: Status,
+}
+
+// Until a node picks a colour of its own.
+pub fn default_colour(node_id: PublicKey) -> [f32; 3] {
+    let hue = node_id.as_bytes()[0] as f32 / 255.0;
+    egui::ecolor::Hsva::new(hue, 0.7, 0.9, 1.0).to_rgb()
+}
+
+impl Presence {
+    pub fn new(node_id: PublicKey, avatar: String) -> Self {
+        Self {
+            display_name: node_id.fmt_short(),
+            colour: default_colour(node_id),
+            avatar,
+            status: Status::Available,
+        }
+    }
+
+    // An `over` of the node's avatar prim with the presence authored as custom attributes.
+    pub fn to_usda(&self, node_id: PublicKey) -> String {
+        let [r, g, b] = self.colour;
+
+        format!(
+            r#"#usda 1.0
+
+over "avatars"
+{{
+    over "{}"
+    {{
+        custom string presence:displayName = {}
+        custom string presence:avatar = {}
+        custom string presence:status = "{}"
+        color3f[] primvars:displayColor = [({}, {}, {})]
+    }}
+}}
+"#,
+            avatar_name(node_id),
+            usda_string(&self.display_name),
+            usda_string(&self.avatar),
+            self.status.as_str(),
+            r,
+            g,
+            b
+        )
+    }
+}
@@ -0,0 +1,48 @@
+use iroh_net::key::PublicKey;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::watch;
+
+// Peers that are currently presenting.
+pub type Presenters = Arc<scc::HashMap<PublicKey, PresenterState>>;
+
+// Sent while presenting. The camera pose itself comes from the presenter's avatar pose.
+#[derive(Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct PresenterState {
+    pub fov_degrees: Option<f32>,
+}
+
+// There should only be one presenter, but if two peers start at once pick the same one
+// everywhere.
+pub fn current_presenter(presenters: &Presenters) -> Option<(PublicKey, PresenterState)> {
+    let mut current: Option<(PublicKey, PresenterState)> = None;
+    presenters.scan(|node_id, presenter_state| {
+        if current
+            .as_ref()
+            .map_or(true, |(current, _)| node_id.as_bytes() < current.as_bytes())
+        {
+            current = Some((*node_id, *presenter_state));
+        }
+    });
+    current
+}
+
+// Peers that started presenting at the same time as us pick the presenter with the lowest key,
+// so stop if that isn't us. Returns the peer we stopped for.
+pub fn yield_presentation(
+    presenters: &Presenters,
+    node_id: PublicKey,
+    presenter_tx: &watch::Sender<Option<PresenterState>>,
+) -> Option<PublicKey> {
+    if presenter_tx.borrow().is_none() {
+        return None;
+    }
+
+    let (presenter, _) = current_presenter(presenters)?;
+    if presenter.as_bytes() > node_id.as_bytes() {
+        return None;
+    }
+
+    presenter_tx.send_replace(None);
+    Some(presenter)
+}
@@ -0,0 +1,175 @@
+use crate::signed_layer::SignedLayer;
+use crate::UsdState;
+use bbl_usd::sdf;
+use iroh_net::key::PublicKey;
+use std::sync::Arc;
+
+// Relayed layers are dropped after passing through this many nodes.
+pub const MAX_HOPS: u8 = 8;
+
+// Layers from authors we haven't approved are ignored once there are this many authors.
+pub const MAX_REMOTE_AUTHORS: usize = 64;
+
+// The layers received from each author, whether directly or through relays.
+pub type RemoteAuthors = Arc<scc::HashMap<PublicKey, RemoteAuthor>>;
+
+#[derive(Clone)]
+pub struct RelayedLayer {
+    // The node we received the layer from.
+    pub from: PublicKey,
+    // How many nodes the layer has passed through, including us.
+    pub hops: u8,
+    pub layer: SignedLayer,
+}
+
+#[derive(Clone)]
+pub struct RemoteAuthor {
+    // Holds the author's sublayers and the presence layers of connections to them.
+    pub root: Arc<sdf::LayerRefPtr>,
+    pub layers: Arc<tokio::sync::Mutex<AuthorLayers>>,
+}
+
+#[derive(Default)]
+pub struct AuthorLayers {
+    pub sublayers: Vec<sdf::LayerRefPtr>,
+    // The last update to each layer, kept to forward to nodes that connect later and to
+    // order the updates that arrive after it. Kept when the author reconnects, as the
+    // epoch in the signed header is what tells a restarted author apart.
+    latest: Vec<Option<SignedLayer>>,
+}
+
+impl AuthorLayers {
+    // Whether a layer is newer than what we have for it. This is what stops relayed layers
+    // from going around in loops, and old ones from being replayed to roll a layer back.
+    pub fn is_new(&self, layer: &SignedLayer) -> bool {
+        match self.latest.get(layer.index as usize) {
+            Some(Some(latest)) => layer.version() > latest.version(),
+            _ => true,
+        }
+    }
+
+    pub fn store(&mut self, layer: SignedLayer) {
+        let index = layer.index as usize;
+        if self.latest.len() <= index {
+            self.latest.resize(index + 1, None);
+        }
+        self.latest[index] = Some(layer);
+    }
+
+    pub fn latest(&self) -> impl Iterator<Item = &SignedLayer> {
+        self.latest.iter().flatten()
+    }
+}
+
+pub async fn remote_author(
+    authors: &RemoteAuthors,
+    usd: &tokio::sync::RwLock<UsdState>,
+    author: PublicKey,
+) -> RemoteAuthor {
+    if let Some(remote_author) = authors
+        .read_async(&author, |_, remote| remote.clone())
+        .await
+    {
+        return remote_author;
+    }
+
+    let usd = usd.write().await;
+
+    // Authors are only added while holding the lock, so check again.
+    if let Some(remote_author) = authors
+        .read_async(&author, |_, remote| remote.clone())
+        .await
+    {
+        return remote_author;
+    }
+
+    let root = Arc::new(sdf::Layer::create_anonymous(".usda"));
+    usd.root_layer.insert_sub_layer_path(
+        root.get_identifier(),
+        crate::layers::LOCAL_ONLY_LAYER_COUNT as _,
+    );
+
+    let remote_author = RemoteAuthor {
+        root,
+        layers: Default::default(),
+    };
+
+    let _ = authors.insert_async(author, remote_author.clone()).await;
+
+    remote_author
+}
+
+// The latest layers from every author, to send to a node that just connected.
+pub async fn stored_layers(authors: &RemoteAuthors) -> Vec<SignedLayer> {
+    let mut remote_authors = Vec::new();
+    authors
+        .scan_async(|_, remote_author| remote_authors.push(remote_author.clone()))
+        .await;
+
+    let mut layers = Vec::new();
+    for remote_author in remote_authors {
+        layers.extend(
+            remote_author
+                .layers
+                .lock()
+                .await
+                .latest
+                .iter()
+                .flatten()
+                .cloned(),
+        );
+    }
+    layers
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh_net::key::SecretKey;
+
+    fn layer(
+        key: &SecretKey,
+        epoch: u64,
+        index: u32,
+        update_index: u32,
+        data: &str,
+    ) -> SignedLayer {
+        SignedLayer::sign(key, epoch, index, update_index, data.as_bytes().to_vec())
+    }
+
+    #[test]
+    fn updates_must_be_newer() {
+        let key = SecretKey::generate();
+        let mut layers = AuthorLayers::default();
+
+        let first = layer(&key, 1, 0, 1, "a");
+        assert!(layers.is_new(&first));
+        layers.store(first.clone());
+        assert!(!layers.is_new(&first));
+        assert!(!layers.is_new(&layer(&key, 1, 0, 0, "older")));
+
+        // Each layer is ordered on its own.
+        assert!(layers.is_new(&layer(&key, 1, 1, 1, "b")));
+        assert!(layers.is_new(&layer(&key, 1, 0, 2, "c")));
+    }
+
+    #[test]
+    fn old_updates_cant_be_replayed_after_reconnecting() {
+        let key = SecretKey::generate();
+        let mut layers = AuthorLayers::default();
+
+        let old = layer(&key, 1, 0, 5, "old");
+        layers.store(old.clone());
+        layers.store(layer(&key, 1, 0, 6, "new"));
+
+        // Nothing is reset when the author reconnects, so a relay can't send the old update
+        // again.
+        assert!(!layers.is_new(&old));
+
+        // A restarted author counts from zero again, in a later epoch.
+        let restarted = layer(&key, 2, 0, 0, "restarted");
+        assert!(layers.is_new(&restarted));
+        layers.store(restarted);
+        assert!(!layers.is_new(&layer(&key, 1, 0, 7, "from the old run")));
+    }
+}
@@ -0,0 +1,42 @@
+use iroh_net::key::PublicKey;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+
+// The roles that peers have granted us.
+pub type GrantedRoles = Arc<scc::HashMap<PublicKey, Role>>;
+
+// Granted to a node when approving it.
+// Ordered from least to most trusted.
+#[derive(
+    Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug, Serialize, Deserialize, clap::ValueEnum,
+)]
+pub enum Role {
+    // Only their avatar is taken from their layers.
+    Viewer,
+    Editor,
+    // Can also approve and kick nodes on our behalf.
+    Admin,
+}
+
+impl Role {
+    pub const ALL: [Self; 3] = [Self::Viewer, Self::Editor, Self::Admin];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Viewer => "viewer",
+            Self::Editor => "editor",
+            Self::Admin => "admin",
+        }
+    }
+
+    pub fn can_edit(&self) -> bool {
+        *self != Self::Viewer
+    }
+}
+
+// Sent by an admin to every node that made it one.
+#[derive(Clone, Serialize, Deserialize)]
+pub enum AdminCommand {
+    Approve { node_id: PublicKey, role: Role },
+    Kick { node_id: PublicKey },
+}
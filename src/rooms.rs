@@ -0,0 +1,162 @@
+use crate::assets::AssetManifest;
+use crate::avatars::PoseSample;
+use crate::base_scene::BaseScene;
+use crate::layers::LocalLayers;
+use crate::presenter::PresenterState;
+use crate::{ipc, networking, UsdState};
+use bbl_usd::{sdf, usd};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+// Each room has an ALPN of its own, so connections to a room the other node doesn't serve
+// fail when connecting.
+const ALPN_PREFIX: &str = "usd-render/room/";
+
+pub fn alpn(room: &str) -> Vec<u8> {
+    format!("{}{}", ALPN_PREFIX, room).into_bytes()
+}
+
+pub fn room_from_alpn(alpn: &str) -> Option<&str> {
+    alpn.strip_prefix(ALPN_PREFIX)
+}
+
+// Room names end up in ALPNs and tickets, so keep them simple.
+pub fn validate_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+    {
+        return Err(anyhow::anyhow!(
+            "Invalid room name {:?}: use letters, digits, '-' and '_'",
+            name
+        ));
+    }
+
+    Ok(())
+}
+
+// The networking state of every room served by our endpoint, by name.
+pub type Rooms = Arc<HashMap<String, networking::State>>;
+
+// `<name>=<base>`, for `--host-room`.
+#[derive(Clone, Debug)]
+pub struct HostedRoomArg {
+    pub name: String,
+    pub base: String,
+}
+
+impl FromStr for HostedRoomArg {
+    type Err = anyhow::Error;
+
+    fn from_str(string: &str) -> anyhow::Result<Self> {
+        let (name, base) = string
+            .split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected <name>=<base>, got {:?}", string))?;
+
+        validate_name(name)?;
+
+        Ok(Self {
+            name: name.to_string(),
+            base: base.to_string(),
+        })
+    }
+}
+
+// A room that is served without being displayed. Peers' layers are composed into a stage of
+// its own and relayed between them, but nothing is authored locally.
+pub struct HostedRoom {
+    pub state: networking::State,
+    _local_layers: LocalLayers,
+    _layer_state: watch::Sender<ipc::PublicLayerState>,
+    _pose: watch::Sender<PoseSample>,
+    _presenter: watch::Sender<Option<PresenterState>>,
+    _asset_manifest: watch::Sender<AssetManifest>,
+}
+
+impl HostedRoom {
+    // Every field of the room's state is listed, so that new state has to be either shared
+    // with the displayed room or made the room's own.
+    pub fn new(room: HostedRoomArg, displayed: &networking::State) -> anyhow::Result<Self> {
+        let stage = usd::Stage::create_in_memory();
+        let root_layer = stage.get_root_layer();
+
+        let base_layer = sdf::Layer::find_or_open(&room.base);
+        root_layer.insert_sub_layer_path(base_layer.get_identifier(), 0);
+
+        // Keeps the layer stack the same shape as in the displayed room.
+        let local_layers = LocalLayers::new(&root_layer);
+
+        let (layer_state, layer_state_rx) = watch::channel(ipc::PublicLayerState {
+            layers: Vec::new(),
+            layer_update_indices: Vec::new(),
+            update_index: 0,
+        });
+        let (pose, pose_rx) = watch::channel(PoseSample::default());
+        let (presenter, presenter_rx) = watch::channel(None);
+        let (asset_manifest, asset_manifest_rx) = watch::channel(AssetManifest::default());
+
+        let state = networking::State {
+            // Shared with the displayed room: the endpoint and what belongs to us rather than
+            // to a room.
+            endpoint: displayed.endpoint.clone(),
+            approval_queue: displayed.approval_queue.clone(),
+            approval_requests: displayed.approval_requests.clone(),
+            address_book: displayed.address_book.clone(),
+            invites: displayed.invites.clone(),
+            presence: displayed.presence.clone(),
+            interest: displayed.interest.clone(),
+            bandwidth: displayed.bandwidth.clone(),
+            default_acl: displayed.default_acl.clone(),
+            relay: displayed.relay,
+            base_mismatch: displayed.base_mismatch,
+            // Assets fetched for the room are cached and can be passed on, but nothing of
+            // our own is referenced.
+            assets: displayed.assets.clone(),
+            asset_manifest: asset_manifest_rx,
+            // The rules are shared, but introductions only count in the room they were made in.
+            trust: displayed.trust.for_room(),
+            // Everything to do with the room's participants and layers is its own.
+            room: room.name,
+            base_scene: Arc::new(BaseScene::hash(&room.base)?),
+            usd: Arc::new(tokio::sync::RwLock::new(UsdState {
+                pseudo_root: stage.pseudo_root(),
+                local_root: local_layers.root_identifier(),
+                root_layer,
+                stage,
+            })),
+            state: layer_state_rx.clone(),
+            session_layers: layer_state_rx,
+            approved_nodes: Default::default(),
+            connected_nodes: Default::default(),
+            participants: Default::default(),
+            chat: Default::default(),
+            pose: pose_rx,
+            avatar_poses: Default::default(),
+            layer_poses: Default::default(),
+            unresolved_avatars: Default::default(),
+            presenter: presenter_rx,
+            presenters: Default::default(),
+            interests: Default::default(),
+            acls: Default::default(),
+            connections: Default::default(),
+            granted_roles: Default::default(),
+            admin_commands: tokio::sync::broadcast::channel(16).0,
+            remote_authors: Default::default(),
+            relayed_layers: tokio::sync::broadcast::channel(100).0,
+            remote_assets: Default::default(),
+            pending_reloads: Default::default(),
+        };
+
+        Ok(Self {
+            state,
+            _local_layers: local_layers,
+            _layer_state: layer_state,
+            _pose: pose,
+            _presenter: presenter,
+            _asset_manifest: asset_manifest,
+        })
+    }
+}
@@ -0,0 +1,203 @@
+use crate::relay::{self, RemoteAuthors};
+use crate::signed_layer::SignedLayer;
+use iroh_net::key::{PublicKey, Signature};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+
+const MANIFEST: &str = "manifest.bin";
+// Bumped whenever the manifest's layout changes, as postcard can't tell layouts apart.
+const MANIFEST_VERSION: u32 = 2;
+const PRIVATE_LAYER: &str = "private.usda";
+
+#[derive(Serialize, Deserialize)]
+struct PublicLayerFile {
+    index: u32,
+    file: String,
+}
+
+// Remote layers keep their signatures, so that they can still be relayed after resuming.
+#[derive(Serialize, Deserialize)]
+struct RemoteLayerFile {
+    author: PublicKey,
+    epoch: u64,
+    index: u32,
+    update_index: u32,
+    signature: Vec<u8>,
+    file: String,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+    // Always first, so that it can be read without knowing the rest of the layout.
+    version: u32,
+    node_id: PublicKey,
+    public_layers: Vec<PublicLayerFile>,
+    remote_layers: Vec<RemoteLayerFile>,
+}
+
+pub struct SavedSession {
+    // The node that saved the session.
+    pub node_id: PublicKey,
+    // By layer index, in order.
+    pub public_layers: Vec<(usize, String)>,
+    pub private_layer: String,
+    pub remote_layers: Vec<SignedLayer>,
+}
+
+// Write our public sublayers, our private layer and the latest layers from every remote
+// author to a directory, with a manifest to put them back together with. The files are
+// written on the blocking pool.
+pub async fn save(
+    directory: PathBuf,
+    node_id: PublicKey,
+    public_layers: Vec<String>,
+    private_layer: String,
+    remote_authors: &RemoteAuthors,
+) -> anyhow::Result<()> {
+    let remote_layers = relay::stored_layers(remote_authors).await;
+
+    tokio::task::spawn_blocking(move || {
+        write(
+            &directory,
+            node_id,
+            &public_layers,
+            &private_layer,
+            remote_layers,
+        )
+    })
+    .await?
+}
+
+fn write(
+    directory: &Path,
+    node_id: PublicKey,
+    public_layers: &[String],
+    private_layer: &str,
+    remote_layers: Vec<SignedLayer>,
+) -> anyhow::Result<()> {
+    std::fs::create_dir_all(directory)?;
+
+    let mut manifest = Manifest {
+        version: MANIFEST_VERSION,
+        node_id,
+        public_layers: Vec::new(),
+        remote_layers: Vec::new(),
+    };
+
+    for (index, layer) in public_layers.iter().enumerate() {
+        let file = format!("public_{}.usda", index);
+        std::fs::write(directory.join(&file), layer)?;
+        manifest.public_layers.push(PublicLayerFile {
+            index: index as u32,
+            file,
+        });
+    }
+
+    std::fs::write(directory.join(PRIVATE_LAYER), private_layer)?;
+
+    for layer in remote_layers {
+        let file = format!("remote_{}_{}.usda", layer.author, layer.index);
+        std::fs::write(directory.join(&file), &layer.data)?;
+        manifest.remote_layers.push(RemoteLayerFile {
+            author: layer.author,
+            epoch: layer.epoch,
+            index: layer.index,
+            update_index: layer.update_index,
+            signature: layer.signature.to_bytes().to_vec(),
+            file,
+        });
+    }
+
+    std::fs::write(directory.join(MANIFEST), postcard::to_stdvec(&manifest)?)?;
+
+    log::info!(
+        "Saved {} public and {} remote layers to {}",
+        manifest.public_layers.len(),
+        manifest.remote_layers.len(),
+        directory.display()
+    );
+
+    Ok(())
+}
+
+fn decode_manifest(data: &[u8]) -> anyhow::Result<Manifest> {
+    let (version, _): (u32, _) = postcard::take_from_bytes(data)?;
+    if version != MANIFEST_VERSION {
+        anyhow::bail!(
+            "The session was saved with manifest version {}, but only version {} can be loaded",
+            version,
+            MANIFEST_VERSION
+        );
+    }
+    Ok(postcard::from_bytes(data)?)
+}
+
+pub fn load(directory: &Path) -> anyhow::Result<SavedSession> {
+    let manifest: Manifest = decode_manifest(&std::fs::read(directory.join(MANIFEST))?)?;
+
+    let mut public_layers = manifest
+        .public_layers
+        .into_iter()
+        .map(|layer| {
+            Ok((
+                layer.index as usize,
+                std::fs::read_to_string(directory.join(layer.file))?,
+            ))
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+    public_layers.sort_by_key(|(index, _)| *index);
+
+    let remote_layers = manifest
+        .remote_layers
+        .into_iter()
+        .map(|layer| {
+            let signature: [u8; 64] = layer.signature.as_slice().try_into().map_err(|_| {
+                anyhow::anyhow!("Invalid signature for {} in the manifest", layer.file)
+            })?;
+
+            Ok(SignedLayer {
+                author: layer.author,
+                epoch: layer.epoch,
+                index: layer.index,
+                update_index: layer.update_index,
+                signature: Signature::from_bytes(&signature),
+                data: std::fs::read(directory.join(layer.file))?,
+            })
+        })
+        .collect::<anyhow::Result<Vec<_>>>()?;
+
+    Ok(SavedSession {
+        node_id: manifest.node_id,
+        public_layers,
+        private_layer: std::fs::read_to_string(directory.join(PRIVATE_LAYER))?,
+        remote_layers,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use iroh_net::key::SecretKey;
+
+    #[test]
+    fn manifest_versions() {
+        let manifest = Manifest {
+            version: MANIFEST_VERSION,
+            node_id: SecretKey::generate().public(),
+            public_layers: vec![PublicLayerFile {
+                index: 0,
+                file: "public_0.usda".to_string(),
+            }],
+            remote_layers: Vec::new(),
+        };
+        let decoded = decode_manifest(&postcard::to_stdvec(&manifest).unwrap()).unwrap();
+        assert_eq!(decoded.node_id, manifest.node_id);
+        assert_eq!(decoded.public_layers[0].file, "public_0.usda");
+
+        let newer = Manifest {
+            version: MANIFEST_VERSION + 1,
+            ..manifest
+        };
+        assert!(decode_manifest(&postcard::to_stdvec(&newer).unwrap()).is_err());
+    }
+}
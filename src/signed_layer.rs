@@ -0,0 +1,121 @@
+use iroh_net::key::{PublicKey, SecretKey, Signature};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+// Identifies this run of the app in the layers we sign. Update indices start again from zero
+// on every run, so peers order updates by epoch first. Milliseconds since the unix epoch, so
+// later runs have higher epochs as long as the clock doesn't go backwards.
+pub fn session_epoch() -> u64 {
+    static EPOCH: std::sync::OnceLock<u64> = std::sync::OnceLock::new();
+    *EPOCH.get_or_init(|| {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64)
+    })
+}
+
+// A layer update signed by the node that authored it, so that its authorship can be
+// verified whichever node it was received from.
+#[derive(Clone)]
+pub struct SignedLayer {
+    pub author: PublicKey,
+    // The author's `session_epoch` when the layer was signed.
+    pub epoch: u64,
+    pub index: u32,
+    pub update_index: u32,
+    pub signature: Signature,
+    pub data: Vec<u8>,
+}
+
+impl SignedLayer {
+    // author + epoch + index + update index + signature.
+    pub const HEADER_LEN: usize = 32 + 8 + 4 + 4 + 64;
+
+    pub fn sign(
+        secret_key: &SecretKey,
+        epoch: u64,
+        index: u32,
+        update_index: u32,
+        data: Vec<u8>,
+    ) -> Self {
+        let author = secret_key.public();
+        let signature = secret_key.sign(&Self::message(author, epoch, index, update_index, &data));
+
+        Self {
+            author,
+            epoch,
+            index,
+            update_index,
+            signature,
+            data,
+        }
+    }
+
+    // The epoch, index and update index are signed along with the data so that an update
+    // can't be replayed into a different layer or as a newer update.
+    fn message(
+        author: PublicKey,
+        epoch: u64,
+        index: u32,
+        update_index: u32,
+        data: &[u8],
+    ) -> Vec<u8> {
+        let mut message = Vec::with_capacity(48 + data.len());
+        message.extend_from_slice(author.as_bytes());
+        message.extend_from_slice(&epoch.to_le_bytes());
+        message.extend_from_slice(&index.to_le_bytes());
+        message.extend_from_slice(&update_index.to_le_bytes());
+        message.extend_from_slice(data);
+        message
+    }
+
+    pub fn verify(&self) -> anyhow::Result<()> {
+        self.author
+            .verify(
+                &Self::message(
+                    self.author,
+                    self.epoch,
+                    self.index,
+                    self.update_index,
+                    &self.data,
+                ),
+                &self.signature,
+            )
+            .map_err(|error| {
+                anyhow::anyhow!(
+                    "Invalid signature on layer {} from {}: {}",
+                    self.index,
+                    self.author.fmt_short(),
+                    error
+                )
+            })
+    }
+
+    pub fn header(&self) -> [u8; Self::HEADER_LEN] {
+        let mut header = [0; Self::HEADER_LEN];
+        header[..32].copy_from_slice(self.author.as_bytes());
+        header[32..40].copy_from_slice(&self.epoch.to_le_bytes());
+        header[40..44].copy_from_slice(&self.index.to_le_bytes());
+        header[44..48].copy_from_slice(&self.update_index.to_le_bytes());
+        header[48..].copy_from_slice(&self.signature.to_bytes());
+        header
+    }
+
+    pub fn from_header(header: &[u8; Self::HEADER_LEN], data: Vec<u8>) -> anyhow::Result<Self> {
+        let u32_at =
+            |start: usize| u32::from_le_bytes(header[start..start + 4].try_into().unwrap());
+
+        Ok(Self {
+            author: PublicKey::from_bytes(header[..32].try_into().unwrap())?,
+            epoch: u64::from_le_bytes(header[32..40].try_into().unwrap()),
+            index: u32_at(40),
+            update_index: u32_at(44),
+            signature: Signature::from_bytes(header[48..].try_into().unwrap()),
+            data,
+        })
+    }
+
+    // Updates to a layer are ordered by this.
+    pub fn version(&self) -> (u64, u32) {
+        (self.epoch, self.update_index)
+    }
+}
@@ -0,0 +1,141 @@
+use crate::networking::ApprovedNodes;
+use crate::roles::Role;
+use iroh_net::key::PublicKey;
+use std::collections::HashSet;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::Arc;
+
+// Rules for approving nodes without asking.
+#[derive(clap::Args, Debug, Clone)]
+pub struct TrustRules {
+    /// Approve nodes introduced by this node. Can be given multiple times.
+    #[arg(long = "trust-referrer")]
+    pub referrers: Vec<PublicKey>,
+    /// Approve nodes introduced by at least this many approved nodes.
+    #[arg(long = "trust-introductions")]
+    pub min_introductions: Option<usize>,
+    /// File of node ids to approve, one per line. Re-read on every check, so it can be
+    /// shared and kept in sync between peers.
+    #[arg(long = "trusted-keys")]
+    pub keys_file: Option<PathBuf>,
+    /// Role given to nodes approved by these rules.
+    #[arg(long = "trusted-role", value_enum, default_value_t = Role::Editor)]
+    pub role: Role,
+    /// The highest role that admins may grant on our behalf.
+    #[arg(long = "admin-max-role", value_enum, default_value_t = Role::Editor)]
+    pub admin_max_role: Role,
+}
+
+#[derive(Clone)]
+pub struct Trust {
+    rules: Arc<TrustRules>,
+    // The nodes that have introduced each node to us.
+    introductions: Arc<scc::HashMap<PublicKey, HashSet<PublicKey>>>,
+}
+
+impl Trust {
+    pub fn new(rules: TrustRules) -> Self {
+        Self {
+            rules: Arc::new(rules),
+            introductions: Default::default(),
+        }
+    }
+
+    // The same rules, with introductions of its own, for another room.
+    pub fn for_room(&self) -> Self {
+        Self {
+            rules: self.rules.clone(),
+            introductions: Default::default(),
+        }
+    }
+
+    pub fn role(&self) -> Role {
+        self.rules.role
+    }
+
+    pub fn admin_max_role(&self) -> Role {
+        self.rules.admin_max_role
+    }
+
+    pub async fn record_introduction(&self, node_id: PublicKey, referrer: PublicKey) {
+        self.introductions
+            .entry_async(node_id)
+            .await
+            .or_default()
+            .get_mut()
+            .insert(referrer);
+    }
+
+    fn trusted_keys(&self) -> HashSet<PublicKey> {
+        let path = match self.rules.keys_file.as_ref() {
+            Some(path) => path,
+            None => return HashSet::new(),
+        };
+
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(error) => {
+                log::error!("Reading trusted keys from {}: {}", path.display(), error);
+                return HashSet::new();
+            }
+        };
+
+        contents
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .filter_map(|line| match PublicKey::from_str(line) {
+                Ok(key) => Some(key),
+                Err(error) => {
+                    log::warn!("Invalid trusted key {:?}: {}", line, error);
+                    None
+                }
+            })
+            .collect()
+    }
+
+    // Why a node should be approved without asking, if it should be.
+    pub async fn reason_to_trust(
+        &self,
+        node_id: PublicKey,
+        approved_nodes: &ApprovedNodes,
+    ) -> Option<String> {
+        if self.trusted_keys().contains(&node_id) {
+            return Some("in the trusted keys file".to_string());
+        }
+
+        let introducers = self
+            .introductions
+            .read_async(&node_id, |_, introducers| introducers.clone())
+            .await
+            .unwrap_or_default();
+
+        if let Some(referrer) = self
+            .rules
+            .referrers
+            .iter()
+            .find(|referrer| introducers.contains(referrer))
+        {
+            return Some(format!("introduced by {}", referrer.fmt_short()));
+        }
+
+        if let Some(min_introductions) = self.rules.min_introductions {
+            let mut approved_introducers = 0;
+            for introducer in &introducers {
+                if approved_nodes.contains_async(introducer).await {
+                    approved_introducers += 1;
+                }
+            }
+
+            if approved_introducers >= min_introductions.max(1) {
+                return Some(format!(
+                    "introduced by {} approved nodes",
+                    approved_introducers
+                ));
+            }
+        }
+
+        None
+    }
+}
@@ -1,13 +1,29 @@
-use crate::networking::{self, NodeApprovalDirection, NodeApprovalResponse, NodeSharingPolicy};
-use crate::util::spawn_fallible;
-use bbl_usd::cpp;
+use crate::acl::PathAcl;
+use crate::address_book::{AddressBook, Contact};
+use crate::approval::{PendingApproval, PendingApprovals};
+use crate::avatars;
+use crate::chat::ChatMessage;
+use crate::commit::{self, CommitMode, CommitOptions, CommitPreview};
+use crate::export::{self, ExportFormat, ExportMode, ExportOptions};
+use crate::interest::AreaOfInterest;
+use crate::invite::{self, InviteOptions};
+use crate::networking::{self, Approval, NodeApprovalResponse, NodeSharingPolicy};
+use crate::presence::{Presence, Status};
+use crate::presenter::{self, PresenterState};
+use crate::roles::{AdminCommand, Role};
+use crate::rooms::Rooms;
+use crate::util::{millis_since_start, spawn_fallible};
 use iroh_net::{key::PublicKey, ticket::NodeTicket, NodeAddr};
-use std::str::FromStr;
-use tokio::sync::oneshot;
+use tokio::sync::watch;
 
-pub fn draw_connection(ui: &mut egui::Ui, connection_info: &iroh_net::magicsock::EndpointInfo) {
+pub fn draw_connection(
+    ui: &mut egui::Ui,
+    connection_info: &iroh_net::magicsock::EndpointInfo,
+    address_book: &AddressBook,
+    state: &mut State,
+) {
     ui.label(connection_info.id.to_string());
-    ui.label(connection_info.public_key.fmt_short());
+    ui.label(address_book.name(&connection_info.public_key));
     ui.label(format!("{}", connection_info.conn_type));
     ui.label(match connection_info.latency {
         Some(duration) => format!("{:.2} ms", duration.as_secs_f32() * 1000.0),
@@ -17,11 +33,23 @@ pub fn draw_connection(ui: &mut egui::Ui, connection_info: &iroh_net::magicsock:
         Some(duration) => format!("{:.2} s", duration.as_secs_f32()),
         None => "Never".to_string(),
     });
+    if address_book.get(&connection_info.public_key).is_none() && ui.button("Add contact").clicked()
+    {
+        state.editing_contact = Some((
+            connection_info.public_key,
+            Contact {
+                last_addr: Some(networking::node_addr_from_info(connection_info)),
+                ..Default::default()
+            },
+        ));
+    }
 }
 
 pub fn draw_connection_grid(
     ui: &mut egui::Ui,
     connection_infos: &[iroh_net::magicsock::EndpointInfo],
+    address_book: &AddressBook,
+    state: &mut State,
 ) {
     if connection_infos.is_empty() {
         ui.label("No current connections");
@@ -30,7 +58,7 @@ pub fn draw_connection_grid(
             .striped(true)
             .show(ui, |ui| {
                 for connection_info in connection_infos {
-                    draw_connection(ui, connection_info);
+                    draw_connection(ui, connection_info, address_book, state);
                     ui.end_row();
                 }
             });
@@ -41,16 +69,101 @@ pub fn draw_node_info(
     ui: &mut egui::Ui,
     addr: &NodeAddr,
     ticket: &NodeTicket,
+    room: &str,
     window: &mut glfw::Window,
 ) {
     ui.label(format!("Node ID: {}", addr.node_id.fmt_short()));
+    ui.label(format!("Room: {}", room));
     ui.label("Node Ticket (click to copy):");
-    let node_ticket_str = ticket.to_string();
+    let node_ticket_str = invite::format_ticket(ticket, room, None);
     if ui.button(&node_ticket_str).clicked() {
         window.set_clipboard_string(&node_ticket_str);
     }
 }
 
+pub fn draw_invites(
+    ui: &mut egui::Ui,
+    networking_state: &networking::State,
+    rooms: &Rooms,
+    ticket: &NodeTicket,
+    state: &mut State,
+    window: &mut glfw::Window,
+) {
+    let options = &mut state.invite_options;
+
+    if !rooms.contains_key(&options.room) {
+        options.room = networking_state.room.clone();
+    }
+
+    ui.horizontal(|ui| {
+        if rooms.len() > 1 {
+            egui::ComboBox::from_label("Room")
+                .selected_text(&options.room)
+                .show_ui(ui, |ui| {
+                    let mut names: Vec<&String> = rooms.keys().collect();
+                    names.sort();
+                    for name in names {
+                        ui.selectable_value(&mut options.room, name.clone(), name);
+                    }
+                });
+        }
+        ui.label("Valid for (minutes): ");
+        ui.add(egui::DragValue::new(&mut options.valid_minutes).clamp_range(1..=60 * 24 * 7));
+        ui.label("Uses: ");
+        ui.add(egui::DragValue::new(&mut options.uses).clamp_range(1..=1000));
+        egui::ComboBox::from_label("Role")
+            .selected_text(options.role.as_str())
+            .show_ui(ui, |ui| {
+                for role in Role::ALL {
+                    ui.selectable_value(&mut options.role, role, role.as_str());
+                }
+            });
+    });
+
+    if ui.button("Create invite (copies to clipboard)").clicked() {
+        let token = networking_state.invites.create(
+            options.room.clone(),
+            std::time::Duration::from_secs(options.valid_minutes as u64 * 60),
+            options.uses,
+            options.role,
+        );
+        window.set_clipboard_string(&invite::format_ticket(ticket, &options.room, Some(&token)));
+    }
+
+    let invites = networking_state.invites.list();
+
+    if invites.is_empty() {
+        return;
+    }
+
+    egui::Grid::new("invites_grid")
+        .striped(true)
+        .show(ui, |ui| {
+            for (token, invite) in invites {
+                let remaining = invite
+                    .expires_at
+                    .duration_since(std::time::SystemTime::now())
+                    .unwrap_or_default();
+
+                ui.label(&invite.room);
+                ui.label(invite.role.as_str());
+                ui.label(format!("{} uses left", invite.remaining_uses));
+                ui.label(format!("expires in {}m", remaining.as_secs().div_ceil(60)));
+                if ui.button("Copy").clicked() {
+                    window.set_clipboard_string(&invite::format_ticket(
+                        ticket,
+                        &invite.room,
+                        Some(&token),
+                    ));
+                }
+                if ui.button("Revoke").clicked() {
+                    networking_state.invites.revoke(&token);
+                }
+                ui.end_row();
+            }
+        });
+}
+
 pub fn draw_connect_to_node(
     ui: &mut egui::Ui,
     networking_state: &networking::State,
@@ -59,7 +172,7 @@ pub fn draw_connect_to_node(
 ) {
     let response = ui
         .horizontal(|ui| {
-            ui.label("Connect to node: ");
+            ui.label("Connect to node or invite: ");
             ui.add(egui::widgets::text_edit::TextEdit::singleline(
                 &mut state.ticket_input,
             ))
@@ -67,16 +180,27 @@ pub fn draw_connect_to_node(
         .inner;
 
     if response.lost_focus() && response.ctx.input(|ctx| ctx.key_pressed(egui::Key::Enter)) {
-        match NodeTicket::from_str(&state.ticket_input) {
-            Ok(ticket) => {
-                let node_addr = ticket.node_addr().clone();
+        match invite::parse_ticket(&state.ticket_input) {
+            Ok(join) => {
+                let node_addr = join.ticket.node_addr().clone();
                 if node_addr == *addr {
                     log::error!("Not connecting to self.");
+                } else if join
+                    .room
+                    .as_ref()
+                    .map_or(false, |room| *room != networking_state.room)
+                {
+                    log::error!(
+                        "Ticket is for room {:?}, but we're in {:?}. Restart with --room to join it.",
+                        join.room.unwrap_or_default(),
+                        networking_state.room
+                    );
                 } else {
                     tokio::spawn(networking::connect(
                         networking_state.clone(),
                         node_addr,
                         None,
+                        join.invite,
                     ));
                     state.ticket_input.clear();
                 }
@@ -88,13 +212,698 @@ pub fn draw_connect_to_node(
     }
 }
 
-pub fn draw_buttons(ui: &mut egui::Ui, networking_state: &networking::State) {
-    if ui.button("export").clicked() {
-        tokio::spawn({
-            let networking_state = networking_state.clone();
+pub fn draw_address_book(
+    ui: &mut egui::Ui,
+    networking_state: &networking::State,
+    state: &mut State,
+) {
+    let address_book = &networking_state.address_book;
+    let contacts = address_book.contacts();
+
+    if contacts.is_empty() {
+        ui.label("No saved contacts");
+    } else {
+        egui::Grid::new("address_book_grid")
+            .striped(true)
+            .show(ui, |ui| {
+                for (node_id, contact) in contacts {
+                    ui.label(&contact.nickname);
+                    ui.label(node_id.fmt_short());
+                    ui.label(&contact.notes);
+
+                    let connected = networking_state.connected_nodes.contains(&node_id);
+
+                    match contact.last_addr.clone() {
+                        Some(node_addr) if !connected => {
+                            if ui.button("Connect").clicked() {
+                                tokio::spawn(networking::connect(
+                                    networking_state.clone(),
+                                    node_addr,
+                                    None,
+                                    None,
+                                ));
+                            }
+                        }
+                        Some(_) => {
+                            ui.label("Connected");
+                        }
+                        None => {
+                            ui.label("No known address");
+                        }
+                    }
+
+                    if ui.button("Edit").clicked() {
+                        state.editing_contact = Some((node_id, contact));
+                    }
+
+                    if ui.button("Remove").clicked() {
+                        address_book.remove(&node_id);
+                    }
+
+                    ui.end_row();
+                }
+            });
+    }
+
+    let mut close = false;
+
+    if let Some((node_id, contact)) = state.editing_contact.as_mut() {
+        ui.separator();
+        ui.label(format!("Editing {}", node_id.fmt_short()));
+        ui.horizontal(|ui| {
+            ui.label("Nickname: ");
+            ui.text_edit_singleline(&mut contact.nickname);
+        });
+        ui.horizontal(|ui| {
+            ui.label("Notes: ");
+            ui.text_edit_multiline(&mut contact.notes);
+        });
+        ui.horizontal(|ui| {
+            if ui.button("Save").clicked() {
+                let mut contact = contact.clone();
+                // Keep any address learned since editing started.
+                if let Some(existing) = address_book.get(node_id) {
+                    contact.last_addr = existing.last_addr.or(contact.last_addr);
+                }
+                address_book.set(*node_id, contact);
+                close = true;
+            }
+            if ui.button("Cancel").clicked() {
+                close = true;
+            }
+        });
+    }
+
+    if close {
+        state.editing_contact = None;
+    }
+}
+
+fn draw_colour_swatch(ui: &mut egui::Ui, colour: [f32; 3]) {
+    let (rect, _) = ui.allocate_exact_size(egui::vec2(12.0, 12.0), egui::Sense::hover());
+    ui.painter().rect_filled(
+        rect,
+        2.0,
+        egui::Rgba::from_rgb(colour[0], colour[1], colour[2]),
+    );
+}
+
+pub fn draw_participants(
+    ui: &mut egui::Ui,
+    presence_tx: &watch::Sender<Presence>,
+    networking_state: &networking::State,
+    state: &mut State,
+) {
+    let mut presence = presence_tx.borrow().clone();
+
+    ui.horizontal(|ui| {
+        ui.label("Display name: ");
+        ui.text_edit_singleline(&mut presence.display_name);
+    });
+    ui.horizontal(|ui| {
+        ui.label("Colour: ");
+        ui.color_edit_button_rgb(&mut presence.colour);
+        egui::ComboBox::from_label("Status")
+            .selected_text(presence.status.as_str())
+            .show_ui(ui, |ui| {
+                for status in Status::ALL {
+                    ui.selectable_value(&mut presence.status, status, status.as_str());
+                }
+            });
+    });
+    ui.horizontal(|ui| {
+        ui.label("Avatar: ");
+        ui.add(egui::TextEdit::singleline(&mut state.avatar_input).hint_text(&presence.avatar));
+        if ui.button("Browse").clicked() {
+            let picked_avatar = state.picked_avatar.clone();
+            tokio::spawn(async move {
+                // None if cancelled.
+                if let Some(filehandle) = rfd::AsyncFileDialog::new()
+                    .add_filter("usd", &["usd", "usda", "usdc", "usdz"])
+                    .pick_file()
+                    .await
+                {
+                    *picked_avatar.lock().unwrap() =
+                        Some(filehandle.path().to_string_lossy().into_owned());
+                }
+            });
+        }
+        if ui.button("Set").clicked() && !state.avatar_input.is_empty() {
+            if std::path::Path::new(&state.avatar_input).exists() {
+                presence.avatar = std::mem::take(&mut state.avatar_input);
+            } else {
+                log::error!("No avatar at {}", state.avatar_input);
+            }
+        }
+    });
+
+    if let Some(picked) = state.picked_avatar.lock().unwrap().take() {
+        presence.avatar = picked;
+    }
+
+    presence_tx.send_if_modified(|current| {
+        if *current == presence {
+            return false;
+        }
+
+        *current = presence;
+        true
+    });
+
+    ui.separator();
+
+    let mut participants = Vec::new();
+    networking_state
+        .participants
+        .scan(|node_id, presence| participants.push((*node_id, presence.clone())));
+    participants.sort_by(|(_, a), (_, b)| a.display_name.cmp(&b.display_name));
+
+    if participants.is_empty() {
+        ui.label("No other participants");
+        return;
+    }
+
+    egui::Grid::new("participants_grid")
+        .striped(true)
+        .show(ui, |ui| {
+            for (node_id, presence) in participants {
+                draw_colour_swatch(ui, presence.colour);
+                ui.label(&presence.display_name);
+                ui.label(networking_state.address_book.name(&node_id));
+                ui.label(presence.status.as_str());
+                ui.label(&presence.avatar);
+                if state.following == Some(node_id) {
+                    if ui.button("Unfollow").clicked() {
+                        state.following = None;
+                    }
+                } else if ui
+                    .add_enabled(
+                        avatars::composed_pose(
+                            &networking_state.avatar_poses,
+                            &networking_state.layer_poses,
+                            &node_id,
+                            millis_since_start(),
+                        )
+                        .is_some(),
+                        egui::Button::new("Follow"),
+                    )
+                    .clicked()
+                {
+                    state.following = Some(node_id);
+                }
+                ui.end_row();
+            }
+        });
+}
+
+fn format_acl(acl: &PathAcl) -> String {
+    match acl.allowed_prefixes.as_ref() {
+        None => "Anywhere".to_string(),
+        Some(prefixes) if prefixes.is_empty() => "Own avatar only".to_string(),
+        Some(prefixes) => prefixes.join(", "),
+    }
+}
+
+// Where each approved peer may author. Changes apply to layers received afterwards.
+pub fn draw_permissions(
+    ui: &mut egui::Ui,
+    networking_state: &networking::State,
+    state: &mut State,
+) {
+    let mut peers = Vec::new();
+    networking_state
+        .approved_nodes
+        .scan(|node_id, approval| peers.push((*node_id, approval.role)));
+    peers.sort_by_key(|(node_id, _)| networking_state.address_book.name(node_id));
+
+    if peers.is_empty() {
+        ui.label("No approved peers");
+    } else {
+        egui::Grid::new("permissions_grid")
+            .striped(true)
+            .show(ui, |ui| {
+                for (node_id, role) in peers {
+                    let acl = networking_state
+                        .acls
+                        .read(&node_id, |_, acl| acl.clone())
+                        .unwrap_or_else(|| networking_state.default_acl.clone());
+
+                    ui.label(networking_state.address_book.name(&node_id));
+                    ui.label(role.as_str());
+                    match networking_state
+                        .granted_roles
+                        .read(&node_id, |_, role| *role)
+                    {
+                        Some(granted) => ui.label(format!("made us {}", granted.as_str())),
+                        None => ui.label(""),
+                    };
+                    if role.can_edit() {
+                        ui.label(format_acl(&acl));
+                        if ui.button("Edit").clicked() {
+                            state.editing_acl = Some((
+                                node_id,
+                                acl.unrestricted(),
+                                acl.allowed_prefixes.unwrap_or_default().join(", "),
+                            ));
+                        }
+                    } else {
+                        ui.label("Own avatar only");
+                        ui.label("");
+                    }
+                    if ui.button("Kick").clicked() {
+                        // Errors if there are no connections, which is fine.
+                        let _ = networking_state
+                            .admin_commands
+                            .send(AdminCommand::Kick { node_id });
+                        tokio::spawn({
+                            let networking_state = networking_state.clone();
+                            async move {
+                                networking::kick(&networking_state, node_id).await;
+                            }
+                        });
+                    }
+                    ui.end_row();
+                }
+            });
+    }
+
+    let mut close = false;
+
+    if let Some((node_id, unrestricted, prefixes)) = state.editing_acl.as_mut() {
+        ui.separator();
+        ui.label(format!(
+            "Editing {}",
+            networking_state.address_book.name(node_id)
+        ));
+        ui.checkbox(unrestricted, "Author anywhere");
+        ui.add_enabled_ui(!*unrestricted, |ui| {
+            ui.horizontal(|ui| {
+                ui.label("Allowed paths: ");
+                ui.text_edit_singleline(prefixes).on_hover_text(
+                    "Comma separated prim paths, e.g. /shared. Their own avatar is always allowed.",
+                );
+            });
+        });
+        ui.horizontal(|ui| {
+            if ui.button("Save").clicked() {
+                let acl = PathAcl {
+                    allowed_prefixes: (!*unrestricted).then(|| {
+                        prefixes
+                            .split(',')
+                            .map(|prefix| prefix.trim().to_string())
+                            .filter(|prefix| !prefix.is_empty())
+                            .collect()
+                    }),
+                };
+                networking_state.acls.entry(*node_id).insert_entry(acl);
+                close = true;
+            }
+            if ui.button("Cancel").clicked() {
+                close = true;
+            }
+        });
+    }
+
+    if close {
+        state.editing_acl = None;
+    }
+}
+
+pub fn draw_chat(ui: &mut egui::Ui, networking_state: &networking::State, state: &mut State) {
+    egui::containers::scroll_area::ScrollArea::vertical()
+        .max_height(200.0)
+        .stick_to_bottom(true)
+        .show(ui, |ui| {
+            egui::Grid::new("chat").striped(true).show(ui, |ui| {
+                for entry in networking_state.chat.history().iter() {
+                    ui.label(entry.message.format_time());
+                    ui.label(networking_state.address_book.name(&entry.sender));
+                    ui.label(&entry.message.text);
+                    ui.end_row();
+                }
+            })
+        });
+
+    let response = ui.text_edit_singleline(&mut state.chat_input);
+
+    if response.lost_focus()
+        && response.ctx.input(|ctx| ctx.key_pressed(egui::Key::Enter))
+        && !state.chat_input.is_empty()
+    {
+        let node_id = networking_state.endpoint.node_id();
+        let message = ChatMessage::new(std::mem::take(&mut state.chat_input));
+        log::info!(
+            "[chat] {}: {}",
+            networking_state.address_book.name(&node_id),
+            message.escaped_text()
+        );
+        networking_state.chat.send(node_id, message);
+        response.request_focus();
+    }
+}
+
+pub fn draw_area_of_interest(ui: &mut egui::Ui, interest_tx: &watch::Sender<AreaOfInterest>) {
+    let mut interest = *interest_tx.borrow();
+
+    ui.add(egui::Slider::new(&mut interest.full_rate_radius, 0.0..=100.0).text("Full rate radius"));
+
+    let mut limited = interest.max_radius.is_some();
+    ui.checkbox(&mut limited, "Ignore far away avatars");
+    if limited {
+        let max_radius = interest
+            .max_radius
+            .get_or_insert(interest.full_rate_radius * 2.0);
+        ui.add(egui::Slider::new(max_radius, 0.0..=500.0).text("Max radius"));
+    } else {
+        interest.max_radius = None;
+    }
+
+    interest_tx.send_if_modified(|current| {
+        if *current == interest {
+            return false;
+        }
+
+        *current = interest;
+        true
+    });
+}
+
+pub fn draw_presenter(
+    ui: &mut egui::Ui,
+    presenter_tx: &watch::Sender<Option<PresenterState>>,
+    networking_state: &networking::State,
+    state: &mut State,
+    fov_degrees: &mut f32,
+) {
+    let mut presenting = presenter_tx.borrow().is_some();
+
+    if let Some(presenter) = state.yielded_presentation_to {
+        ui.colored_label(
+            egui::Color32::LIGHT_RED,
+            format!(
+                "You stopped presenting, as {} started presenting at the same time",
+                networking_state.address_book.name(&presenter)
+            ),
+        );
+    }
+
+    if presenting {
+        ui.label("You are presenting");
+        ui.checkbox(&mut state.share_fov, "Share field of view");
+        if ui.button("Stop presenting").clicked() {
+            presenting = false;
+        }
+    } else if let Some((presenter, _)) = presenter::current_presenter(&networking_state.presenters)
+    {
+        ui.label(format!(
+            "{} is presenting",
+            networking_state.address_book.name(&presenter)
+        ));
+        if state.following_presenter == Some(presenter) {
+            if ui.button("Stop following (F)").clicked() {
+                state.following_presenter = None;
+            }
+        } else if ui.button("Follow presentation").clicked() {
+            state.following_presenter = Some(presenter);
+        }
+    } else if ui.button("Present").clicked() {
+        presenting = true;
+        state.yielded_presentation_to = None;
+    }
+
+    ui.add(egui::Slider::new(fov_degrees, 20.0..=120.0).text("Field of view"));
+
+    let presenter_state = presenting.then_some(PresenterState {
+        fov_degrees: state.share_fov.then_some(*fov_degrees),
+    });
+
+    presenter_tx.send_if_modified(|current| {
+        if *current == presenter_state {
+            return false;
+        }
+
+        *current = presenter_state;
+        true
+    });
+}
+
+// Always visible while presenting or following a presentation.
+pub fn draw_presenter_indicator(
+    ctx: &egui::Context,
+    presenter_tx: &watch::Sender<Option<PresenterState>>,
+    networking_state: &networking::State,
+    state: &State,
+) {
+    let text = if presenter_tx.borrow().is_some() {
+        "Presenting".to_string()
+    } else if let Some(presenter) = state.following_presenter {
+        format!(
+            "Following {}'s presentation. Press F to stop.",
+            networking_state.address_book.name(&presenter)
+        )
+    } else {
+        return;
+    };
+
+    egui::Area::new("presenter_indicator")
+        .anchor(egui::Align2::CENTER_TOP, egui::vec2(0.0, 10.0))
+        .show(ctx, |ui| {
+            egui::Frame::popup(ui.style()).show(ui, |ui| {
+                ui.colored_label(egui::Color32::LIGHT_RED, text);
+            });
+        });
+}
+
+pub fn draw_bandwidth(ui: &mut egui::Ui, networking_state: &networking::State, state: &mut State) {
+    let now = std::time::Instant::now();
+    let totals = networking_state.bandwidth.totals();
+
+    // Update the rates about once a second.
+    let elapsed = state
+        .bandwidth_sample
+        .as_ref()
+        .map(|(time, _)| now.duration_since(*time).as_secs_f64());
+
+    match (elapsed, state.bandwidth_sample.as_ref()) {
+        (Some(elapsed), Some((_, previous))) if elapsed >= 1.0 => {
+            state.bandwidth_rates = totals
+                .iter()
+                .zip(previous)
+                .map(|((_, total), (_, previous))| (total - previous) as f64 / elapsed)
+                .collect();
+            state.bandwidth_sample = Some((now, totals.clone()));
+        }
+        (None, _) => {
+            state.bandwidth_sample = Some((now, totals.clone()));
+        }
+        _ => {}
+    }
+
+    egui::Grid::new("bandwidth_grid")
+        .striped(true)
+        .show(ui, |ui| {
+            ui.label("Packet type");
+            ui.label("Sent");
+            ui.label("Rate");
+            ui.end_row();
+
+            for (index, (name, total)) in totals.iter().enumerate() {
+                ui.label(*name);
+                ui.label(format!("{:.1} KiB", *total as f64 / 1024.0));
+                ui.label(match state.bandwidth_rates.get(index) {
+                    Some(rate) => format!("{:.2} KiB/s", rate / 1024.0),
+                    None => "N/A".to_string(),
+                });
+                ui.end_row();
+            }
+        });
+}
+
+pub fn draw_export(
+    ui: &mut egui::Ui,
+    networking_state: &networking::State,
+    base: &str,
+    state: &mut State,
+) {
+    let options = &mut state.export_options;
+
+    egui::ComboBox::from_label("Mode")
+        .selected_text(options.mode.as_str())
+        .show_ui(ui, |ui| {
+            for mode in ExportMode::ALL {
+                ui.selectable_value(&mut options.mode, mode, mode.as_str());
+            }
+        });
+    egui::ComboBox::from_label("Format")
+        .selected_text(options.format.extension())
+        .show_ui(ui, |ui| {
+            for format in ExportFormat::ALL {
+                ui.add_enabled_ui(options.mode.supports(format), |ui| {
+                    ui.selectable_value(&mut options.format, format, format.extension());
+                });
+            }
+        });
+    if !options.mode.supports(options.format) {
+        options.format = ExportFormat::Usda;
+    }
+    if options.mode == ExportMode::LayerStack && options.format == ExportFormat::Usda {
+        ui.label("Each layer is written to a directory next to the file.");
+    }
+
+    if ui.button("Export...").clicked() {
+        let options: ExportOptions = *options;
+        let networking_state = networking_state.clone();
+        let base = base.to_string();
+        spawn_fallible(
             async move {
-                let state = networking_state.usd.read().await;
-                state.stage.export(&cpp::String::new("export.usdc"));
+                let extension = options.format.extension();
+                // The dialog asks before overwriting an existing file. None if cancelled.
+                if let Some(filehandle) = rfd::AsyncFileDialog::new()
+                    .add_filter(extension, &[extension])
+                    .set_file_name(format!("export.{}", extension))
+                    .save_file()
+                    .await
+                {
+                    let path = filehandle.path().with_extension(extension);
+                    export::export(&networking_state, &base, &path, options).await?;
+                }
+                Ok(())
+            },
+            |error| async move {
+                log::error!("Export failed: {}", error);
+            },
+        );
+    }
+}
+
+pub fn draw_commit(
+    ui: &mut egui::Ui,
+    networking_state: &networking::State,
+    base: &str,
+    state: &mut State,
+) {
+    let options = &mut state.commit_options;
+    ui.checkbox(&mut options.ours, "Our edits");
+
+    let mut authors = Vec::new();
+    networking_state
+        .remote_authors
+        .scan(|author, _| authors.push(*author));
+    // Authors that left can't be committed any more.
+    options.authors.retain(|author| authors.contains(author));
+
+    for author in authors {
+        let name = networking_state
+            .participants
+            .read(&author, |_, presence| presence.display_name.clone())
+            .filter(|name| !name.is_empty())
+            .unwrap_or_else(|| author.fmt_short());
+        let mut included = options.authors.contains(&author);
+        if ui.checkbox(&mut included, name).changed() {
+            if included {
+                options.authors.insert(author);
+            } else {
+                options.authors.remove(&author);
+            }
+        }
+    }
+
+    egui::ComboBox::from_label("Commit as")
+        .selected_text(options.mode.as_str())
+        .show_ui(ui, |ui| {
+            for mode in CommitMode::ALL {
+                ui.selectable_value(&mut options.mode, mode, mode.as_str());
+            }
+        });
+    if options.mode == CommitMode::Flatten {
+        ui.label("The base layer's references and payloads are flattened too.");
+    }
+
+    if ui.button("Preview").clicked() {
+        let options = options.clone();
+        let networking_state = networking_state.clone();
+        let commit_preview = state.commit_preview.clone();
+        spawn_fallible(
+            async move {
+                let preview = commit::preview(&networking_state, &options).await?;
+                *commit_preview.lock().unwrap() = Some(std::sync::Arc::new(preview));
+                Ok(())
+            },
+            |error| async move {
+                log::error!("Commit preview failed: {}", error);
+            },
+        );
+    }
+
+    // A stale preview would commit edits that weren't chosen.
+    let preview = match state.commit_preview.lock().unwrap().clone() {
+        Some(preview) if preview.is_for(options) => preview,
+        _ => return,
+    };
+
+    ui.label(format!(
+        "{} layers change {} prims:",
+        preview.layer_count,
+        preview.changes.len()
+    ));
+    egui::ScrollArea::vertical()
+        .id_source("commit_preview")
+        .max_height(200.0)
+        .show(ui, |ui| {
+            for change in &preview.changes {
+                let kind = if change.defined { "defined" } else { "changed" };
+                ui.label(format!("{} ({})", change.path, kind));
+                for property in &change.properties {
+                    ui.label(format!("    .{}", property));
+                }
+            }
+        });
+
+    if ui.button("Commit to base").clicked() {
+        let mode = options.mode;
+        let base = base.to_string();
+        let commit_preview = state.commit_preview.clone();
+        let cache = networking_state.assets.clone();
+        spawn_fallible(
+            async move {
+                let sublayer = match mode {
+                    CommitMode::NewSublayer => {
+                        let directory = std::path::Path::new(&base)
+                            .parent()
+                            .unwrap_or(std::path::Path::new(""))
+                            .to_owned();
+                        // None if cancelled.
+                        match rfd::AsyncFileDialog::new()
+                            .add_filter("usda", &["usda"])
+                            .set_directory(directory)
+                            .set_file_name("edits.usda")
+                            .save_file()
+                            .await
+                        {
+                            Some(filehandle) => Some(filehandle.path().with_extension("usda")),
+                            None => return Ok(()),
+                        }
+                    }
+                    CommitMode::Flatten => None,
+                };
+                commit::commit(&base, &preview, mode, sublayer.as_deref(), &cache)?;
+                *commit_preview.lock().unwrap() = None;
+                Ok(())
+            },
+            |error| async move {
+                log::error!("Commit failed: {}", error);
+            },
+        );
+    }
+}
+
+pub fn draw_buttons(ui: &mut egui::Ui, networking_state: &networking::State, state: &mut State) {
+    if ui.button("save session").clicked() {
+        let save_session_to = state.save_session_to.clone();
+        tokio::spawn(async move {
+            // None if cancelled.
+            if let Some(filehandle) = rfd::AsyncFileDialog::new().pick_folder().await {
+                *save_session_to.lock().unwrap() = Some(filehandle.path().to_owned());
             }
         });
     }
@@ -119,64 +928,129 @@ pub fn draw_buttons(ui: &mut egui::Ui, networking_state: &networking::State) {
     }
 }
 
+const APPROVAL_PAGE_SIZE: usize = 10;
+
 pub fn draw_approval_queue(
     ui: &mut egui::Ui,
     state: &mut State,
-    approved_nodes: &scc::HashMap<PublicKey, NodeSharingPolicy>,
+    pending_approvals: &PendingApprovals,
+    rooms: &Rooms,
+    address_book: &AddressBook,
 ) {
-    ui.heading("Approval Queue");
+    let mut approval_queue = pending_approvals.lock();
+
+    // The connection closed, the request timed out or it was answered elsewhere.
+    approval_queue.retain(PendingApproval::is_waiting);
+
+    if approval_queue.is_empty() {
+        return;
+    }
+
+    ui.heading(format!("Approval Queue ({})", approval_queue.len()));
+
+    let page_count = approval_queue.len().div_ceil(APPROVAL_PAGE_SIZE);
+    state.approval_page = state.approval_page.min(page_count.saturating_sub(1));
 
-    state.approval_queue.retain(|(node_id, direction, sender)| {
-        if sender.is_none() {
+    if page_count > 1 {
+        ui.horizontal(|ui| {
+            if ui
+                .add_enabled(state.approval_page > 0, egui::Button::new("Previous"))
+                .clicked()
+            {
+                state.approval_page -= 1;
+            }
+            ui.label(format!(
+                "Page {} of {}",
+                state.approval_page + 1,
+                page_count
+            ));
+            if ui
+                .add_enabled(
+                    state.approval_page + 1 < page_count,
+                    egui::Button::new("Next"),
+                )
+                .clicked()
+            {
+                state.approval_page += 1;
+            }
+        });
+    }
+
+    let page =
+        state.approval_page * APPROVAL_PAGE_SIZE..(state.approval_page + 1) * APPROVAL_PAGE_SIZE;
+    let mut index = 0;
+
+    approval_queue.retain_mut(|pending| {
+        // Approved elsewhere, e.g. by an admin.
+        let approval = rooms.get(&pending.room).and_then(|room| {
+            room.approved_nodes
+                .get(&pending.node_id)
+                .map(|approval| approval.get().clone())
+        });
+        if let Some(approval) = approval {
+            pending.respond(NodeApprovalResponse::Approved(approval));
             return false;
         }
 
-        if let Some(node_sharing) = approved_nodes.get(node_id) {
-            let _ = sender
-                .take()
-                .unwrap()
-                .send(NodeApprovalResponse::Approved(node_sharing.get().clone()));
-            return false;
+        index += 1;
+        if !page.contains(&(index - 1)) {
+            return true;
         }
 
         ui.horizontal(|ui| {
-            ui.label(node_id.fmt_short());
-            match direction {
+            ui.label(address_book.name(&pending.node_id));
+            if rooms.len() > 1 {
+                ui.label(format!("room {}", pending.room));
+            }
+            match &pending.direction {
                 networking::NodeApprovalDirection::Incoming => {
                     ui.label("Incoming");
                 }
                 networking::NodeApprovalDirection::Outgoing { referrer } => {
                     ui.label(format!(
                         "Outgoing (referred to by {})",
-                        referrer.fmt_short()
+                        address_book.name(referrer)
                     ));
                 }
             }
+            ui.label(format!(
+                "waiting {}s",
+                pending.requested_at.elapsed().as_secs()
+            ));
+
+            egui::ComboBox::from_id_source(("approval_role", pending.node_id))
+                .selected_text(pending.role.as_str())
+                .show_ui(ui, |ui| {
+                    for option in Role::ALL {
+                        ui.selectable_value(&mut pending.role, option, option.as_str());
+                    }
+                });
 
-            let mut retain = true;
+            let mut response = None;
 
             if ui.button("Allow").clicked() {
-                let _ = sender.take().unwrap().send(NodeApprovalResponse::Approved(
-                    NodeSharingPolicy::AllExcept(Default::default()),
-                ));
-                retain = false;
+                response = Some(NodeApprovalResponse::Approved(Approval {
+                    sharing: NodeSharingPolicy::AllExcept(Default::default()),
+                    role: pending.role,
+                }));
             }
-
             if ui.button("Allow (private)").clicked() {
-                let _ = sender.take().unwrap().send(NodeApprovalResponse::Approved(
-                    NodeSharingPolicy::NoneExcept(Default::default()),
-                ));
-                retain = false;
+                response = Some(NodeApprovalResponse::Approved(Approval {
+                    sharing: NodeSharingPolicy::NoneExcept(Default::default()),
+                    role: pending.role,
+                }));
             }
             if ui.button("Deny").clicked() {
-                let _ = sender
-                    .take()
-                    .unwrap()
-                    .send(networking::NodeApprovalResponse::Denied);
-                retain = false;
+                response = Some(NodeApprovalResponse::Denied);
             }
 
-            retain
+            match response {
+                Some(response) => {
+                    pending.respond(response);
+                    false
+                }
+                None => true,
+            }
         })
         .inner
     });
@@ -184,13 +1058,27 @@ pub fn draw_approval_queue(
 
 #[derive(Default)]
 pub struct State {
-    pub approval_queue: arrayvec::ArrayVec<
-        (
-            PublicKey,
-            NodeApprovalDirection,
-            Option<oneshot::Sender<NodeApprovalResponse>>,
-        ),
-        10,
-    >,
+    pub approval_page: usize,
+    pub invite_options: InviteOptions,
     pub ticket_input: String,
+    pub editing_contact: Option<(PublicKey, Contact)>,
+    // Node, whether it may author anywhere, and comma separated allowed paths.
+    pub editing_acl: Option<(PublicKey, bool, String)>,
+    pub chat_input: String,
+    pub following: Option<PublicKey>,
+    pub following_presenter: Option<PublicKey>,
+    // Who we stopped presenting for, when we both started at once.
+    pub yielded_presentation_to: Option<PublicKey>,
+    pub share_fov: bool,
+    pub bandwidth_sample: Option<(std::time::Instant, Vec<(&'static str, u64)>)>,
+    pub bandwidth_rates: Vec<f64>,
+    pub avatar_input: String,
+    // Set by the file dialog, which finishes after the frame that opened it.
+    pub picked_avatar: std::sync::Arc<std::sync::Mutex<Option<String>>>,
+    // Saved by the main loop, which has the local layers.
+    pub save_session_to: std::sync::Arc<std::sync::Mutex<Option<std::path::PathBuf>>>,
+    pub export_options: ExportOptions,
+    pub commit_options: CommitOptions,
+    // Computed in the background, then shown until the options change.
+    pub commit_preview: std::sync::Arc<std::sync::Mutex<Option<std::sync::Arc<CommitPreview>>>>,
 }
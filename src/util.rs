@@ -37,3 +37,157 @@ pub fn spawn_fallible<
         }
     })
 }
+
+// note: prefix with _avatar as names can't start with numbers.
+pub fn avatar_name(node_id: iroh_net::key::PublicKey) -> String {
+    format!("avatar_{}", node_id.fmt_short())
+}
+
+pub fn avatar_path(node_id: iroh_net::key::PublicKey) -> String {
+    format!("/avatars/{}", avatar_name(node_id))
+}
+
+// Quote and escape a string for use in a usda file.
+pub fn usda_string(string: &str) -> String {
+    let mut quoted = String::with_capacity(string.len() + 2);
+    quoted.push('"');
+    for character in string.chars() {
+        match character {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            character => quoted.push(character),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+// A monotonic clock shared by everything that timestamps data.
+pub fn millis_since_start() -> u64 {
+    static START: std::sync::OnceLock<std::time::Instant> = std::sync::OnceLock::new();
+    START
+        .get_or_init(std::time::Instant::now)
+        .elapsed()
+        .as_millis() as u64
+}
+
+// If a string, asset path or comment starts at `index` in usda source, the index just past it.
+pub fn skip_usda_literal(source: &[u8], index: usize) -> Option<usize> {
+    let rest = source.get(index..)?;
+
+    let (open, close): (&[u8], &[u8]) = match rest.first()? {
+        b'"' if rest.starts_with(b"\"\"\"") => (b"\"\"\"", b"\"\"\""),
+        b'\'' if rest.starts_with(b"'''") => (b"'''", b"'''"),
+        b'@' if rest.starts_with(b"@@@") => (b"@@@", b"@@@"),
+        b'"' => (b"\"", b"\""),
+        b'\'' => (b"'", b"'"),
+        b'@' => (b"@", b"@"),
+        b'#' => (b"#", b"\n"),
+        _ => return None,
+    };
+
+    let mut position = index + open.len();
+    while position < source.len() {
+        if source[position] == b'\\' && open != b"@" && open != b"#" {
+            position += 2;
+            continue;
+        }
+        if source[position..].starts_with(close) {
+            return Some(position + close.len());
+        }
+        position += 1;
+    }
+
+    Some(source.len())
+}
+
+// Index just past the bracket matching the one at `index` in usda source, if it's closed.
+pub fn usda_closing_bracket(source: &[u8], mut index: usize) -> Option<usize> {
+    let mut depth = 0_usize;
+
+    while index < source.len() {
+        if let Some(next) = skip_usda_literal(source, index) {
+            index = next;
+            continue;
+        }
+
+        match source[index] {
+            b'(' | b'[' | b'{' => depth += 1,
+            b')' | b']' | b'}' => {
+                depth = depth.checked_sub(1)?;
+                if depth == 0 {
+                    return Some(index + 1);
+                }
+            }
+            _ => {}
+        }
+        index += 1;
+    }
+
+    None
+}
+
+// The end of a statement starting at `index` in usda source: the end of the line it
+// finishes on, not counting newlines inside brackets or literals.
+pub fn usda_statement_end(source: &[u8], mut index: usize, end: usize) -> usize {
+    let mut depth = 0_usize;
+
+    while index < end {
+        if let Some(next) = skip_usda_literal(source, index) {
+            // Comments end at a newline, which also ends the statement.
+            if source[index] == b'#' && depth == 0 {
+                return next.min(end);
+            }
+            index = next;
+            continue;
+        }
+
+        match source[index] {
+            b'(' | b'[' | b'{' => depth += 1,
+            b')' | b']' | b'}' => depth = depth.saturating_sub(1),
+            b'\n' if depth == 0 => return index + 1,
+            _ => {}
+        }
+        index += 1;
+    }
+
+    end
+}
+
+// Whether a prim's `def`, `over` or `class` starts at `index` in usda source.
+pub fn is_usda_prim_start(source: &[u8], index: usize) -> bool {
+    ["def", "over", "class"].iter().any(|specifier| {
+        source[index..].starts_with(specifier.as_bytes())
+            && source
+                .get(index + specifier.len())
+                .map_or(false, |byte| byte.is_ascii_whitespace())
+    })
+}
+
+// Index of the first `{` at depth 0 after `index` in usda source, and the name of the prim,
+// which is the last string before it.
+pub fn usda_prim_header(source: &[u8], mut index: usize, end: usize) -> Option<(usize, &str)> {
+    let mut depth = 0_usize;
+    let mut name = None;
+
+    while index < end {
+        if let Some(next) = skip_usda_literal(source, index) {
+            if depth == 0 && source[index] == b'"' {
+                name = std::str::from_utf8(&source[index + 1..next - 1]).ok();
+            }
+            index = next;
+            continue;
+        }
+
+        match source[index] {
+            b'(' | b'[' => depth += 1,
+            b')' | b']' => depth = depth.saturating_sub(1),
+            b'{' if depth == 0 => return Some((index, name?)),
+            _ => {}
+        }
+        index += 1;
+    }
+
+    None
+}